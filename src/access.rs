@@ -0,0 +1,275 @@
+use crate::{
+    auth::{Claims, Role},
+    error::{db_error, Error},
+    helpers::{door_id, find_door},
+    middleware::ClaimsExt,
+    prisma::{
+        AccessGrant, AccessGrantCreateInput, AccessGrantUpdateInput,
+        AccessGrantUpdateInputPermission, AccessGrantWhereInput, AccessGrantWhereInputDoorId,
+        AccessGrantWhereInputUserId, AccessGrantWhereUniqueInput, FindFirstAccessGrantArgs,
+        FindManyAccessGrantArgs, UpdateOneAccessGrantArgs, User, UserWhereUniqueInput,
+    },
+    GrantRequest, TideState,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, fmt, sync::Arc};
+use tide::{Body, Error as TideError, Request, StatusCode};
+
+/// What a user may do with a door. Each level includes the ones below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    View,
+    Open,
+    Manage,
+}
+
+impl Permission {
+    pub fn from_str(permission: &str) -> std::result::Result<Permission, ()> {
+        let permission = match permission.to_lowercase().as_str() {
+            "view" => Permission::View,
+            "open" => Permission::Open,
+            "manage" => Permission::Manage,
+            _ => Err(())?,
+        };
+        Ok(permission)
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let permission = match self {
+            Permission::View => "view",
+            Permission::Open => "open",
+            Permission::Manage => "manage",
+        };
+        write!(f, "{}", permission)
+    }
+}
+
+pub fn require_admin(claims: &Claims) -> tide::Result<()> {
+    if claims.role != Role::Admin {
+        Err(Error::NoPermissionError.into_tide())?
+    }
+    Ok(())
+}
+
+/// Returns the permission `claims` hold on `door_id`, if any. Admins manage every door.
+pub async fn permission_for(
+    state: &TideState,
+    claims: &Claims,
+    door_id: i32,
+) -> tide::Result<Option<Permission>> {
+    if claims.role == Role::Admin {
+        return Ok(Some(Permission::Manage));
+    }
+
+    let grant = state
+        .prisma
+        .first_access_grant::<AccessGrant>(FindFirstAccessGrantArgs {
+            filter: Some(AccessGrantWhereInput {
+                user_id: Some(AccessGrantWhereInputUserId::Int(claims.sub as i32)),
+                door_id: Some(AccessGrantWhereInputDoorId::Int(door_id)),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?;
+
+    Ok(grant.and_then(|grant| Permission::from_str(&grant.permission).ok()))
+}
+
+/// Fails with 403 unless `claims` hold at least `required` on `door_id`.
+pub async fn authorize(
+    state: &TideState,
+    claims: &Claims,
+    door_id: i32,
+    required: Permission,
+) -> tide::Result<()> {
+    match permission_for(state, claims, door_id).await? {
+        Some(permission) if permission >= required => Ok(()),
+        _ => Err(TideError::from_str(
+            StatusCode::Forbidden,
+            Error::NoPermissionError,
+        )),
+    }
+}
+
+/// Users holding any grant on `door_id`, which lets them at least view it.
+/// Admins are not included.
+pub async fn grantees(state: &TideState, door_id: i32) -> tide::Result<HashSet<i32>> {
+    let grants = state
+        .prisma
+        .access_grants::<AccessGrant>(FindManyAccessGrantArgs {
+            filter: Some(AccessGrantWhereInput {
+                door_id: Some(AccessGrantWhereInputDoorId::Int(door_id)),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?;
+
+    Ok(grants
+        .into_iter()
+        .filter(|grant| Permission::from_str(&grant.permission).is_ok())
+        .map(|grant| grant.user_id)
+        .collect())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GrantResponse {
+    pub user_id: i32,
+    pub door_id: i32,
+    pub permission: String,
+}
+
+impl From<AccessGrant> for GrantResponse {
+    fn from(grant: AccessGrant) -> Self {
+        GrantResponse {
+            user_id: grant.user_id,
+            door_id: grant.door_id,
+            permission: grant.permission,
+        }
+    }
+}
+
+async fn find_grant(
+    state: &TideState,
+    user_id: i32,
+    door_id: i32,
+) -> tide::Result<Option<AccessGrant>> {
+    state
+        .prisma
+        .first_access_grant::<AccessGrant>(FindFirstAccessGrantArgs {
+            filter: Some(AccessGrantWhereInput {
+                user_id: Some(AccessGrantWhereInputUserId::Int(user_id)),
+                door_id: Some(AccessGrantWhereInputDoorId::Int(door_id)),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)
+}
+
+pub async fn list_grants(req: Request<Arc<TideState>>) -> tide::Result {
+    let claims = req.claims()?;
+    let door_id = door_id(&req)?;
+    authorize(req.state(), &claims, door_id, Permission::Manage).await?;
+
+    let grants = req
+        .state()
+        .prisma
+        .access_grants::<AccessGrant>(FindManyAccessGrantArgs {
+            filter: Some(AccessGrantWhereInput {
+                door_id: Some(AccessGrantWhereInputDoorId::Int(door_id)),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?
+        .into_iter()
+        .map(GrantResponse::from)
+        .collect::<Vec<_>>();
+
+    let mut res = tide::Response::new(StatusCode::Ok);
+    res.set_body(Body::from_json(&grants)?);
+    Ok(res)
+}
+
+pub async fn put_grant(mut req: Request<Arc<TideState>>) -> tide::Result {
+    let grant_request = req.body_json::<GrantRequest>().await?;
+    let claims = req.claims()?;
+    let door_id = door_id(&req)?;
+    authorize(req.state(), &claims, door_id, Permission::Manage).await?;
+
+    let permission = Permission::from_str(&grant_request.permission)
+        .map_err(|_e| TideError::from_str(StatusCode::BadRequest, "Unknown permission"))?;
+
+    let door = find_door(req.state(), door_id).await?;
+    req.state()
+        .prisma
+        .user::<User>(UserWhereUniqueInput {
+            id: Some(grant_request.user_id),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| TideError::from_str(StatusCode::NotFound, "User not found"))?;
+
+    let grant = match find_grant(req.state(), grant_request.user_id, door.id).await? {
+        Some(grant) => {
+            req.state()
+                .prisma
+                .update_access_grant::<AccessGrant>(UpdateOneAccessGrantArgs {
+                    data: AccessGrantUpdateInput {
+                        permission: Some(AccessGrantUpdateInputPermission::String(
+                            permission.to_string(),
+                        )),
+                        ..Default::default()
+                    },
+                    filter: AccessGrantWhereUniqueInput {
+                        id: Some(grant.id),
+                        ..Default::default()
+                    },
+                })
+                .await
+        }
+        None => {
+            req.state()
+                .prisma
+                .create_access_grant::<AccessGrant>(AccessGrantCreateInput {
+                    user_id: grant_request.user_id,
+                    door_id: door.id,
+                    permission: permission.to_string(),
+                })
+                .await
+        }
+    }
+    .map_err(|e| TideError::from_str(400, format!("Grant invalid: {}", e)))?;
+
+    let mut res = tide::Response::new(StatusCode::Ok);
+    res.set_body(Body::from_json(&GrantResponse::from(grant))?);
+    Ok(res)
+}
+
+pub async fn revoke_grant(req: Request<Arc<TideState>>) -> tide::Result {
+    let claims = req.claims()?;
+    let door_id = door_id(&req)?;
+    authorize(req.state(), &claims, door_id, Permission::Manage).await?;
+
+    let user_id = req.param("user_id")?.parse::<i32>().map_err(|e| {
+        TideError::from_str(StatusCode::BadRequest, format!("Invalid user id: {}", e))
+    })?;
+
+    let grant = find_grant(req.state(), user_id, door_id)
+        .await?
+        .ok_or_else(|| TideError::from_str(StatusCode::NotFound, "Grant not found"))?;
+
+    req.state()
+        .prisma
+        .delete_access_grant::<AccessGrant>(AccessGrantWhereUniqueInput {
+            id: Some(grant.id),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?;
+
+    Ok(tide::Response::new(StatusCode::NoContent))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Permission;
+
+    #[test]
+    fn permissions_are_ordered() {
+        assert!(Permission::Manage > Permission::Open);
+        assert!(Permission::Open > Permission::View);
+        assert_eq!(Permission::from_str("OPEN"), Ok(Permission::Open));
+        assert!(Permission::from_str("admin").is_err());
+    }
+}
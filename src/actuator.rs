@@ -0,0 +1,307 @@
+use crate::{
+    error::Error, helpers::set_door_state, prisma::Door, DoorAction, DoorState, TideState,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tide::{Error as TideError, StatusCode};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+/// Hardware that holds a door shut.
+pub trait LockActuator: Send {
+    fn lock(&mut self) -> Result<(), Error>;
+    fn unlock(&mut self) -> Result<(), Error>;
+}
+
+/// Servo that turns a bolt: minimum position is locked, maximum unlocked.
+#[cfg(feature = "gpio")]
+pub struct ServoActuator(rust_gpiozero::Servo);
+
+#[cfg(feature = "gpio")]
+impl LockActuator for ServoActuator {
+    fn lock(&mut self) -> Result<(), Error> {
+        self.0.min();
+        Ok(())
+    }
+
+    fn unlock(&mut self) -> Result<(), Error> {
+        self.0.max();
+        Ok(())
+    }
+}
+
+/// Relay driving an electric strike, released while the relay is on.
+#[cfg(feature = "gpio")]
+pub struct RelayActuator(rust_gpiozero::DigitalOutputDevice);
+
+#[cfg(feature = "gpio")]
+impl LockActuator for RelayActuator {
+    fn lock(&mut self) -> Result<(), Error> {
+        self.0.off();
+        Ok(())
+    }
+
+    fn unlock(&mut self) -> Result<(), Error> {
+        self.0.on();
+        Ok(())
+    }
+}
+
+/// In-memory lock for development, tests and CI. The flags are shared so a
+/// test can watch the lock or jam it.
+#[derive(Clone, Default)]
+pub struct SimulatedActuator {
+    pub locked: Arc<std::sync::atomic::AtomicBool>,
+    pub jammed: Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl SimulatedActuator {
+    fn set(&mut self, locked: bool) -> Result<(), Error> {
+        use std::sync::atomic::Ordering;
+
+        if self.jammed.load(Ordering::SeqCst) {
+            return Err(Error::ActuatorError("simulated jam".to_string()));
+        }
+        self.locked.store(locked, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl LockActuator for SimulatedActuator {
+    fn lock(&mut self) -> Result<(), Error> {
+        self.set(true)
+    }
+
+    fn unlock(&mut self) -> Result<(), Error> {
+        self.set(false)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ActuatorKind {
+    Servo(u8),
+    Relay(u8),
+    Simulated,
+}
+
+impl ActuatorKind {
+    /// Parses `servo:<pin>`, `relay:<pin>` or `simulated`.
+    pub fn from_str(kind: &str) -> Result<ActuatorKind, Error> {
+        let invalid = || Error::ActuatorError(format!("invalid actuator {}", kind));
+        let mut parts = kind.trim().splitn(2, ':');
+        let name = parts.next().unwrap_or_default();
+        let pin = parts.next().map(|pin| pin.parse::<u8>());
+
+        match (name, pin) {
+            ("servo", Some(Ok(pin))) => Ok(ActuatorKind::Servo(pin)),
+            ("relay", Some(Ok(pin))) => Ok(ActuatorKind::Relay(pin)),
+            ("simulated", None) => Ok(ActuatorKind::Simulated),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn build(&self) -> Result<Box<dyn LockActuator>, Error> {
+        match self {
+            #[cfg(feature = "gpio")]
+            ActuatorKind::Servo(pin) => {
+                Ok(Box::new(ServoActuator(rust_gpiozero::Servo::new(*pin))))
+            }
+            #[cfg(feature = "gpio")]
+            ActuatorKind::Relay(pin) => Ok(Box::new(RelayActuator(
+                rust_gpiozero::DigitalOutputDevice::new(*pin),
+            ))),
+            #[cfg(not(feature = "gpio"))]
+            ActuatorKind::Servo(_) | ActuatorKind::Relay(_) => Err(Error::ActuatorError(
+                "built without the gpio feature".to_string(),
+            )),
+            ActuatorKind::Simulated => Ok(Box::new(SimulatedActuator::default())),
+        }
+    }
+}
+
+/// Actuator for each door. Doors without one cannot be driven; the simulator
+/// is only used where it is configured.
+#[derive(Default)]
+pub struct Actuators {
+    doors: Mutex<HashMap<i32, Arc<AsyncMutex<Box<dyn LockActuator>>>>>,
+}
+
+/// Exclusive use of one door's actuator. Door actions hold it from reading the
+/// door until its new state is stored, so only one of them moves the lock.
+pub type ActuatorGuard = OwnedMutexGuard<Box<dyn LockActuator>>;
+
+fn apply(actuator: &mut dyn LockActuator, action: DoorAction) -> Result<(), Error> {
+    match action {
+        DoorAction::Lock => actuator.lock(),
+        DoorAction::Unlock | DoorAction::Open => actuator.unlock(),
+        // closing is the door swinging shut, nothing to drive
+        DoorAction::Close => Ok(()),
+    }
+}
+
+impl Actuators {
+    /// Builds actuators from a spec such as `1=servo:17,2=relay:27`.
+    pub fn from_spec(spec: &str) -> Result<Actuators, Error> {
+        let actuators = Actuators::default();
+        for entry in spec.split(',').filter(|entry| !entry.trim().is_empty()) {
+            let (door, kind) = entry
+                .split_once('=')
+                .ok_or_else(|| Error::ActuatorError(format!("invalid actuator entry {}", entry)))?;
+            let door = door.trim().parse::<i32>()?;
+            actuators.insert(door, ActuatorKind::from_str(kind)?.build()?);
+        }
+        Ok(actuators)
+    }
+
+    pub fn insert(&self, door_id: i32, actuator: Box<dyn LockActuator>) {
+        self.doors
+            .lock()
+            .unwrap()
+            .insert(door_id, Arc::new(AsyncMutex::new(actuator)));
+    }
+
+    pub fn contains(&self, door_id: i32) -> bool {
+        self.doors.lock().unwrap().contains_key(&door_id)
+    }
+
+    fn get(&self, door_id: i32) -> Result<Arc<AsyncMutex<Box<dyn LockActuator>>>, Error> {
+        self.doors
+            .lock()
+            .unwrap()
+            .get(&door_id)
+            .cloned()
+            .ok_or_else(|| {
+                Error::ActuatorError(format!("no actuator configured for door {}", door_id))
+            })
+    }
+
+    /// Waits until no other action is using the door's actuator.
+    pub async fn claim(&self, door_id: i32) -> Result<ActuatorGuard, Error> {
+        Ok(self.get(door_id)?.lock_owned().await)
+    }
+
+    pub async fn apply(&self, door_id: i32, action: DoorAction) -> Result<(), Error> {
+        let mut actuator = self.claim(door_id).await?;
+        apply(&mut **actuator, action)
+    }
+}
+
+/// Claims the actuator of `door`, refusing doors without one.
+pub async fn claim(state: &TideState, door: &Door) -> tide::Result<ActuatorGuard> {
+    state.actuators.claim(door.id).await.map_err(|_e| {
+        TideError::from_str(
+            StatusCode::ServiceUnavailable,
+            format!("{} has no actuator configured", door.name),
+        )
+    })
+}
+
+/// Drives the claimed hardware for `action` before `door` is moved to its
+/// target state. If the actuator fails the door is marked jammed.
+pub async fn drive(
+    state: &TideState,
+    actuator: &mut ActuatorGuard,
+    door: &Door,
+    action: DoorAction,
+) -> tide::Result<()> {
+    if let Err(e) = apply(&mut **actuator, action) {
+        println!("Actuator for {} failed: {}", door.name, e);
+        set_door_state(state, door, DoorState::Jammed).await?;
+        return Err(TideError::from_str(
+            StatusCode::ServiceUnavailable,
+            format!("{} is jammed: {}", door.name, e),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn parses_actuator_specs() {
+        assert_eq!(
+            ActuatorKind::from_str("servo:17").unwrap(),
+            ActuatorKind::Servo(17)
+        );
+        assert_eq!(
+            ActuatorKind::from_str("relay:27").unwrap(),
+            ActuatorKind::Relay(27)
+        );
+        assert_eq!(
+            ActuatorKind::from_str("simulated").unwrap(),
+            ActuatorKind::Simulated
+        );
+        assert!(ActuatorKind::from_str("servo").is_err());
+        assert!(Actuators::from_spec("1=simulated,2=simulated").is_ok());
+        assert!(Actuators::from_spec("front=simulated").is_err());
+    }
+
+    #[tokio::test]
+    async fn doors_without_an_actuator_are_not_simulated() {
+        let actuators = Actuators::from_spec("1=simulated").unwrap();
+        assert!(actuators.contains(1));
+        assert!(!actuators.contains(2));
+        assert!(actuators.apply(1, DoorAction::Unlock).await.is_ok());
+        assert!(matches!(
+            actuators.apply(2, DoorAction::Unlock).await,
+            Err(Error::ActuatorError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn simulator_follows_actions_until_jammed() {
+        let simulator = SimulatedActuator::default();
+        let actuators = Actuators::default();
+        actuators.insert(1, Box::new(simulator.clone()));
+
+        actuators.apply(1, DoorAction::Lock).await.unwrap();
+        assert!(simulator.locked.load(Ordering::SeqCst));
+        actuators.apply(1, DoorAction::Unlock).await.unwrap();
+        assert!(!simulator.locked.load(Ordering::SeqCst));
+
+        simulator.jammed.store(true, Ordering::SeqCst);
+        assert!(actuators.apply(1, DoorAction::Lock).await.is_err());
+        assert!(!simulator.locked.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn claimed_actuator_lets_one_of_two_opposite_actions_through() {
+        let simulator = SimulatedActuator::default();
+        let actuators = Arc::new(Actuators::default());
+        actuators.insert(1, Box::new(simulator.clone()));
+        simulator.locked.store(true, Ordering::SeqCst);
+        // the stored door: state and version
+        let door = Arc::new(Mutex::new((DoorState::Locked, 0)));
+
+        // what `act` does for a request expecting version 0
+        let attempt = |action: DoorAction| {
+            let (actuators, door) = (actuators.clone(), door.clone());
+            tokio::spawn(async move {
+                let mut actuator = actuators.claim(1).await.unwrap();
+                let version = door.lock().unwrap().1;
+                if version != 0 {
+                    return false;
+                }
+                tokio::task::yield_now().await;
+                apply(&mut **actuator, action).unwrap();
+                *door.lock().unwrap() = (action.target(), version + 1);
+                true
+            })
+        };
+        let unlock = attempt(DoorAction::Unlock);
+        let lock = attempt(DoorAction::Lock);
+        let (unlocked, locked) = (unlock.await.unwrap(), lock.await.unwrap());
+
+        assert!(unlocked ^ locked);
+        let (state, version) = *door.lock().unwrap();
+        assert_eq!(version, 1);
+        assert_eq!(
+            state == DoorState::Locked,
+            simulator.locked.load(Ordering::SeqCst)
+        );
+    }
+}
@@ -1,61 +1,45 @@
-use crate::{
-    error::{self, Error},
-    Result,
-};
+use crate::{error::Error, Result};
 use chrono::Utc;
 use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
 use std::fmt;
-use tide::{
-    http::{headers::AUTHORIZATION, Headers},
-    StatusCode,
-};
 
-#[allow(dead_code)]
-async fn authorize((role, headers): (Role, Headers)) -> Result<usize> {
-    match jwt_from_header(headers) {
-        Ok(jwt) => {
-            let decoded = decode::<Claims>(
-                &jwt,
-                &DecodingKey::from_secret(JWT_SECRET),
-                &Validation::new(Algorithm::HS512),
-            )
-            .map_err(|_| {
-                tide::http::Error::from_str(
-                    StatusCode::BadRequest,
-                    Error::JWTTokenError.to_string(),
-                )
-            })?;
+const BEARER: &str = "Bearer";
 
-            if role == Role::Admin && decoded.claims.role != Role::Admin {
-                return Err(error::Error::Tide(tide::http::Error::from_str(
-                    StatusCode::BadRequest,
-                    Error::NoPermissionError.to_string(),
-                )));
-            }
-
-            Ok(decoded.claims.sub)
-        }
-        Err(e) => return Err(e.into()),
+/// Token from an `Authorization` header. Accepts the standard `Bearer <token>`
+/// as well as the `Bearer: <token>` older app versions send.
+pub fn bearer_token(header: &str) -> Result<&str> {
+    let token = header
+        .strip_prefix(BEARER)
+        .ok_or(Error::InavlidAuthHeaderError)?;
+    let token = token.strip_prefix(':').unwrap_or(token);
+    if !token.starts_with(' ') || token.trim().is_empty() {
+        return Err(Error::InavlidAuthHeaderError);
     }
+    Ok(token.trim())
 }
 
-#[allow(dead_code)]
-fn jwt_from_header(headers: Headers) -> Result<String> {
-    let header = match headers.get(AUTHORIZATION) {
-        Some(v) => v,
-        None => return Err(Error::NoAuthHeaderError),
-    };
-    let auth_header = header.to_string();
-    if !auth_header.starts_with(BEARER) {
-        return Err(Error::InavlidAuthHeaderError);
-    }
-    Ok(auth_header.trim_start_matches(BEARER).to_owned())
+pub fn decode_jwt(token: &str, secret: &[u8]) -> Result<Claims> {
+    decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(secret),
+        &Validation::new(Algorithm::HS512),
+    )
+    .map(|decoded| decoded.claims)
+    .map_err(|_| Error::JWTTokenError)
 }
 
-pub fn create_jwt(uid: usize, role: &Role, email: String) -> Result<String> {
+/// Signs an access token for session `sid`, valid for `ttl`.
+pub fn create_jwt(
+    uid: usize,
+    role: &Role,
+    email: String,
+    sid: i32,
+    ttl: chrono::Duration,
+    secret: &[u8],
+) -> Result<String> {
     let expiration = Utc::now()
-        .checked_add_signed(chrono::Duration::weeks(12))
+        .checked_add_signed(ttl)
         .expect("valid timestamp")
         .timestamp();
 
@@ -64,22 +48,16 @@ pub fn create_jwt(uid: usize, role: &Role, email: String) -> Result<String> {
         role: role.clone(),
         exp: expiration as usize,
         email,
+        sid,
     };
 
     let header = Header::new(Algorithm::HS512);
-    encode(&header, &claims, &EncodingKey::from_secret(JWT_SECRET))
+    encode(&header, &claims, &EncodingKey::from_secret(secret))
         .map_err(|_| Error::JWTTokenCreationError)
 }
 
-#[allow(dead_code)]
-const BEARER: &str = "Bearer ";
-pub const JWT_SECRET: &[u8] = b"secret";
-pub const SALT: [u8; 32] = [
-    80, 166, 186, 240, 120, 151, 49, 155, 56, 42, 67, 81, 68, 108, 7, 35, 136, 46, 20, 235, 238,
-    17, 110, 94, 225, 101, 181, 63, 70, 216, 100, 236,
-];
-
-#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+/// Ordered so that a role satisfies any requirement up to itself.
+#[derive(Clone, PartialEq, PartialOrd, Serialize, Deserialize, Debug)]
 pub enum Role {
     User,
     Admin,
@@ -92,6 +70,23 @@ impl Role {
             _ => Role::User,
         }
     }
+
+    /// Strict counterpart of `from_str` for roles sent by clients.
+    pub fn parse(role: &str) -> Option<Role> {
+        match role.to_lowercase().as_str() {
+            "admin" => Some(Role::Admin),
+            "user" => Some(Role::User),
+            _ => None,
+        }
+    }
+
+    /// The name stored in `User.role`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Admin => "admin",
+        }
+    }
 }
 
 impl fmt::Display for Role {
@@ -103,10 +98,28 @@ impl fmt::Display for Role {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Claims {
     pub sub: usize,
     pub role: Role,
     pub exp: usize,
     pub email: String,
+    /// Session the token was issued for, checked on every request so a revoked
+    /// session stops working before the token expires.
+    pub sid: i32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::bearer_token;
+
+    #[test]
+    fn reads_both_bearer_forms() {
+        assert_eq!(bearer_token("Bearer abc.def").unwrap(), "abc.def");
+        assert_eq!(bearer_token("Bearer: abc.def").unwrap(), "abc.def");
+        assert!(bearer_token("Bearer").is_err());
+        assert!(bearer_token("Bearer: ").is_err());
+        assert!(bearer_token("Bearerabc").is_err());
+        assert!(bearer_token("Basic abc").is_err());
+    }
 }
@@ -0,0 +1,88 @@
+use crate::{
+    access::{authorize, Permission},
+    events::{self, EventResult, NewEvent},
+    helpers::{door_id, find_door, spawn_notification},
+    middleware::ClaimsExt,
+    preferences::{Subject, Topic},
+    prisma::{
+        Door, DoorUpdateInput, DoorUpdateInputBattery, DoorWhereUniqueInput, UpdateOneDoorArgs,
+    },
+    templates::Message,
+    BatteryRequest, Polling, TideState,
+};
+use std::sync::Arc;
+use tide::{Error as TideError, Request, StatusCode};
+
+/// Whether a reading of `percent` should raise a low battery alert: only when
+/// it first drops below `threshold`, not on every report after that.
+pub fn became_low(previous: Option<i32>, percent: i32, threshold: i32) -> bool {
+    percent < threshold && previous.map_or(true, |previous| previous >= threshold)
+}
+
+/// Stores the battery level reported by the lock hardware or its bridge and
+/// notifies once it drops below `low_battery_percent`.
+pub async fn report_battery(mut req: Request<Arc<TideState>>) -> tide::Result {
+    let battery_request = req.body_json::<BatteryRequest>().await?;
+    let claims = req.claims()?;
+    let id = door_id(&req)?;
+    authorize(req.state(), &claims, id, Permission::Manage).await?;
+
+    let percent = battery_request.percent;
+    if !(0..=100).contains(&percent) {
+        return Err(TideError::from_str(
+            StatusCode::BadRequest,
+            "percent must be between 0 and 100",
+        ));
+    }
+
+    let door = find_door(req.state(), id).await?;
+    let updated = req
+        .state()
+        .prisma
+        .update_door::<Door>(UpdateOneDoorArgs {
+            data: DoorUpdateInput {
+                battery: Some(Some(DoorUpdateInputBattery::Int(percent))),
+                ..Default::default()
+            },
+            filter: DoorWhereUniqueInput {
+                id: Some(door.id),
+                ..Default::default()
+            },
+        })
+        .await
+        .map_err(|e| TideError::from_str(400, format!("Door invalid: {}", e)))?;
+
+    let threshold = req.state().config.low_battery_percent;
+    if became_low(door.battery, percent, threshold) {
+        let event = NewEvent::new("low_battery", EventResult::Allowed)
+            .source(&req)
+            .door(door.id)
+            .reason(format!("{}% left", percent));
+        events::record(req.state(), event).await;
+
+        let message = Message::new("door.low_battery")
+            .var("door", &door.name)
+            .var("percent", percent);
+        let subject = Subject::new(Topic::LowBattery).door(door.id);
+        spawn_notification(req.state().clone(), subject, message);
+    }
+
+    let mut res = tide::Response::new(StatusCode::Ok);
+    res.set_body(tide::Body::from_json(&Polling::from(updated))?);
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::became_low;
+
+    #[test]
+    fn alerts_once_when_dropping_below_the_threshold() {
+        assert!(became_low(None, 15, 20));
+        assert!(became_low(Some(21), 19, 20));
+        assert!(!became_low(Some(19), 18, 20));
+        assert!(!became_low(Some(50), 20, 20));
+        // charged in between, so the next drop alerts again
+        assert!(became_low(Some(80), 10, 20));
+    }
+}
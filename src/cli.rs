@@ -0,0 +1,286 @@
+use crate::{
+    config::Config,
+    error::Error,
+    helpers::notification_handler,
+    lockout,
+    preferences::Subject,
+    prisma::{
+        Door, DoorCreateInput, FindManyUserArgs, SortOrder, UpdateOneUserArgs, User,
+        UserCreateInput, UserOrderByInput, UserUpdateInput, UserUpdateInputDisabled,
+        UserWhereUniqueInput,
+    },
+    relock, session,
+    templates::Message,
+    utils::{check_email, check_password},
+    DoorState, Result, TideState,
+};
+use clap::{Args, Parser, Subcommand};
+use std::{io::Write, path::PathBuf, sync::Arc};
+
+/// Door lock server and its admin tools.
+#[derive(Debug, Parser)]
+#[clap(name = "servercode3", version)]
+pub struct Cli {
+    /// Config file (TOML), `doorlock.toml` when present
+    #[clap(short, long, global = true)]
+    pub config: Option<PathBuf>,
+    /// Runs the server when no command is given
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the HTTP server
+    Serve(ServeArgs),
+    /// Apply pending database migrations
+    Migrate {
+        #[clap(long, default_value = "src/prisma/schema.prisma")]
+        schema: PathBuf,
+    },
+    #[clap(flatten)]
+    Admin(AdminCommand),
+}
+
+/// Deployment settings that can be set per run, taking precedence over the
+/// config file and `DOORLOCK_*`. Secrets are left to the environment, where
+/// other users can't read them from the process list.
+#[derive(Debug, Default, Args)]
+pub struct ServeArgs {
+    /// Address to listen on
+    #[clap(short, long)]
+    pub listen: Option<String>,
+    /// Page that password reset links point to
+    #[clap(long)]
+    pub reset_url: Option<String>,
+    /// GPIO pin of the doorbell button
+    #[clap(long)]
+    pub doorbell_pin: Option<u8>,
+}
+
+impl ServeArgs {
+    pub fn apply(self, config: &mut Config) {
+        if let Some(listen) = self.listen {
+            config.listen = listen;
+        }
+        if let Some(reset_url) = self.reset_url {
+            config.reset_url = reset_url;
+        }
+        if let Some(pin) = self.doorbell_pin {
+            config.doorbell.pin = Some(pin);
+        }
+    }
+}
+
+/// Commands that only touch the database and exit.
+#[derive(Debug, Subcommand)]
+pub enum AdminCommand {
+    /// Manage user accounts
+    User {
+        #[clap(subcommand)]
+        command: UserCommand,
+    },
+    /// Manage doors
+    Door {
+        #[clap(subcommand)]
+        command: DoorCommand,
+    },
+    /// Push notifications
+    Push {
+        #[clap(subcommand)]
+        command: PushCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum UserCommand {
+    /// Create an account, asking for the password when it is not given
+    Add {
+        email: String,
+        name: String,
+        #[clap(long)]
+        admin: bool,
+        #[clap(long)]
+        password: Option<String>,
+    },
+    /// List all accounts
+    List,
+    /// Stop an account from logging in
+    Disable { email: String },
+    /// Lift a lockout after too many failed logins
+    Unlock { email: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum DoorCommand {
+    /// Register a door, starting out locked
+    Add {
+        name: String,
+        #[clap(long)]
+        location: Option<String>,
+        /// Seconds after an unlock before the door locks itself
+        #[clap(long)]
+        relock_after: Option<i32>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum PushCommand {
+    /// Send a notification to every registered device
+    Test {
+        #[clap(default_value = "Test notification from the door")]
+        message: String,
+    },
+}
+
+/// Runs `prisma migrate deploy` against `schema`.
+pub fn migrate(schema: &PathBuf) -> Result<()> {
+    let status = std::process::Command::new("npx")
+        .args(&["prisma", "migrate", "deploy", "--schema"])
+        .arg(schema)
+        .status()?;
+
+    if !status.success() {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("prisma migrate deploy failed: {}", status),
+        ))?
+    }
+    Ok(())
+}
+
+fn prompt_password() -> Result<String> {
+    print!("Password: ");
+    std::io::stdout().flush()?;
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    Ok(password.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+/// Runs one of the admin commands against the database, then exits.
+pub async fn run(state: Arc<TideState>, command: AdminCommand) -> Result<()> {
+    match command {
+        AdminCommand::User {
+            command:
+                UserCommand::Add {
+                    email,
+                    name,
+                    admin,
+                    password,
+                },
+        } => {
+            let password = match password {
+                Some(password) => password,
+                None => prompt_password()?,
+            };
+            check_password(&password)?;
+            let email = check_email(&email)?;
+
+            let user = state
+                .prisma
+                .create_user::<User>(UserCreateInput {
+                    email,
+                    name,
+                    password: state.hasher.hash(&password)?,
+                    role: if admin { "admin" } else { "user" }.to_string(),
+                    locale: state.config.locale.clone(),
+                    disabled: false,
+                    must_change_password: false,
+                    failed_logins: 0,
+                    last_failed_login: None,
+                    locked_until: None,
+                })
+                .await?;
+            println!("Created {} ({}) with id {}", user.email, user.role, user.id);
+        }
+        AdminCommand::User {
+            command: UserCommand::List,
+        } => {
+            let users = state
+                .prisma
+                .users::<User>(FindManyUserArgs {
+                    order_by: Some(vec![UserOrderByInput {
+                        id: Some(SortOrder::Asc),
+                        ..Default::default()
+                    }]),
+                    ..Default::default()
+                })
+                .await?;
+            for user in users {
+                println!(
+                    "{:>4}  {:<32} {:<16} {:<6} {}",
+                    user.id,
+                    user.email,
+                    user.name,
+                    user.role,
+                    if user.disabled { "disabled" } else { "" }
+                );
+            }
+        }
+        AdminCommand::User {
+            command: UserCommand::Disable { email },
+        } => {
+            let user = state
+                .prisma
+                .update_user::<User>(UpdateOneUserArgs {
+                    data: UserUpdateInput {
+                        disabled: Some(UserUpdateInputDisabled::Boolean(true)),
+                        ..Default::default()
+                    },
+                    filter: UserWhereUniqueInput {
+                        email: Some(email),
+                        ..Default::default()
+                    },
+                })
+                .await?;
+            let revoked = session::revoke_all(&state, user.id).await?;
+            println!("Disabled {} and ended {} sessions", user.email, revoked);
+        }
+        AdminCommand::User {
+            command: UserCommand::Unlock { email },
+        } => {
+            let user = state
+                .prisma
+                .user::<User>(UserWhereUniqueInput {
+                    email: Some(email.clone()),
+                    ..Default::default()
+                })
+                .await?
+                .ok_or_else(|| Error::InvalidInput(format!("no user with email {}", email)))?;
+            lockout::reset_account(&state, &user).await?;
+            println!("Unlocked {}", user.email);
+        }
+        AdminCommand::Door {
+            command:
+                DoorCommand::Add {
+                    name,
+                    location,
+                    relock_after,
+                },
+        } => {
+            relock::validate_relock_after(relock_after)
+                .map_err(|e| Error::InvalidInput(e.to_string()))?;
+            let door = state
+                .prisma
+                .create_door::<Door>(DoorCreateInput {
+                    name,
+                    location,
+                    state: DoorState::Locked.to_string(),
+                    version: 0,
+                    relock_after,
+                    relock_at: None,
+                    battery: None,
+                })
+                .await?;
+            println!("Created {} with id {}", door.name, door.id);
+        }
+        AdminCommand::Push {
+            command: PushCommand::Test { message },
+        } => {
+            let message = Message::new("push_test").var("message", message);
+            notification_handler(state, Subject::everyone(), message).await?;
+            println!("Notification sent");
+        }
+    }
+    Ok(())
+}
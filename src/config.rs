@@ -0,0 +1,526 @@
+use crate::{error::Error, templates::check_locale};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    time::Duration,
+};
+
+/// Server settings, read from a TOML file and then overridden by `DOORLOCK_*`
+/// environment variables and command line flags.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: String,
+    pub jwt_secret: String,
+    /// Lifetime of access tokens; clients renew them with their refresh token.
+    pub access_token_minutes: i64,
+    /// A session ends when its refresh token goes unused for this long.
+    pub refresh_token_days: i64,
+    pub password: PasswordSettings,
+    pub login: LoginSettings,
+    /// Reverse proxies whose `Forwarded` and `X-Forwarded-For` headers are
+    /// believed. Without any, clients are told apart by their own address.
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Page the password reset email links to; the token is appended as `?token=`.
+    pub reset_url: String,
+    /// How long a password reset link stays valid.
+    pub reset_token_minutes: i64,
+    /// How long the link in an invite email stays valid.
+    pub invite_token_hours: i64,
+    /// Token for creating the first admin through `/setup`. A random one is
+    /// printed on startup when this is unset and no admin exists.
+    pub setup_token: Option<String>,
+    /// Language of users created without one and of notification channels.
+    pub locale: String,
+    /// Battery level, in percent, below which a door's users are alerted.
+    pub low_battery_percent: i32,
+    pub mailgun: Option<MailgunConfig>,
+    pub apns: Option<ApnsConfig>,
+    pub fcm: Option<FcmConfig>,
+    pub ntfy: Option<NtfyConfig>,
+    pub gotify: Option<GotifyConfig>,
+    pub webhook: Option<WebhookConfig>,
+    pub notify: NotifySettings,
+    pub doorbell: DoorbellSettings,
+    /// Lock hardware by door id, e.g. `1 = "servo:17"`. Unlisted doors cannot be
+    /// driven; `"simulated"` has to be listed like any other actuator.
+    pub actuators: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MailgunConfig {
+    pub domain: String,
+    pub api_key: String,
+    pub sender: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApnsConfig {
+    /// Path of the `.p8` token signing key.
+    pub key_file: String,
+    pub team_id: String,
+    pub key_id: String,
+    pub topic: Option<String>,
+    #[serde(default)]
+    pub sandbox: bool,
+}
+
+/// Firebase Cloud Messaging (HTTP v1) for Android devices.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FcmConfig {
+    pub project_id: String,
+    /// Service account key (JSON) downloaded from the Firebase console.
+    pub service_account_file: String,
+}
+
+/// ntfy server that devices registered with platform `ntfy` subscribe to; their
+/// token is the topic.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NtfyConfig {
+    pub server: String,
+    /// Access token for servers that require one.
+    pub token: Option<String>,
+}
+
+/// Gotify application every notification is posted to.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GotifyConfig {
+    pub server: String,
+    pub app_token: String,
+    #[serde(default = "default_gotify_priority")]
+    pub priority: u8,
+}
+
+fn default_gotify_priority() -> u8 {
+    5
+}
+
+/// URL every notification is posted to as JSON, e.g. for home automation.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Sent as a bearer token so the receiver can tell the requests are ours.
+    pub secret: Option<String>,
+}
+
+/// Argon2id cost parameters for new password hashes. Stored hashes made with
+/// other parameters are upgraded the next time their owner logs in.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PasswordSettings {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+/// Brute-force protection for `/login`. After the free attempts every further
+/// failure doubles the wait before the next attempt is heard, starting at
+/// `base_delay_secs`; an account is locked once it reaches `lockout_after`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoginSettings {
+    pub free_attempts: u32,
+    pub base_delay_secs: u64,
+    pub max_delay_secs: u64,
+    pub lockout_after: u32,
+    pub lockout_minutes: i64,
+    /// Failures one address may make, across all accounts, before it is slowed down.
+    /// Addresses are forgotten after `lockout_minutes` without failures.
+    pub ip_free_attempts: u32,
+}
+
+/// Delivery of push notifications. Sends failing for a transient reason are
+/// retried after `retry_delay_ms`, doubling with every further attempt.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotifySettings {
+    /// Notifications in flight at once when notifying many devices.
+    pub max_concurrent: usize,
+    pub retries: u32,
+    pub retry_delay_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DoorbellSettings {
+    pub pin: Option<u8>,
+    pub door_id: Option<i32>,
+    pub debounce_ms: u64,
+    pub min_interval_secs: u64,
+    pub message: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: "0.0.0.0:8080".to_string(),
+            jwt_secret: String::new(),
+            access_token_minutes: 15,
+            refresh_token_days: 30,
+            password: PasswordSettings::default(),
+            login: LoginSettings::default(),
+            trusted_proxies: Vec::new(),
+            reset_url: "http://localhost:3000/email".to_string(),
+            reset_token_minutes: 30,
+            invite_token_hours: 72,
+            setup_token: None,
+            locale: "en".to_string(),
+            low_battery_percent: 20,
+            mailgun: None,
+            apns: None,
+            fcm: None,
+            ntfy: None,
+            gotify: None,
+            webhook: None,
+            notify: NotifySettings::default(),
+            doorbell: DoorbellSettings::default(),
+            actuators: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for PasswordSettings {
+    fn default() -> Self {
+        PasswordSettings {
+            memory_kib: 19456,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+impl Default for LoginSettings {
+    fn default() -> Self {
+        LoginSettings {
+            free_attempts: 3,
+            base_delay_secs: 1,
+            max_delay_secs: 300,
+            lockout_after: 10,
+            lockout_minutes: 15,
+            ip_free_attempts: 20,
+        }
+    }
+}
+
+impl Default for NotifySettings {
+    fn default() -> Self {
+        NotifySettings {
+            max_concurrent: 8,
+            retries: 2,
+            retry_delay_ms: 500,
+        }
+    }
+}
+
+impl Default for DoorbellSettings {
+    fn default() -> Self {
+        DoorbellSettings {
+            pin: None,
+            door_id: None,
+            debounce_ms: 100,
+            min_interval_secs: 30,
+            message: "Someone is waiting for you at the door!".to_string(),
+        }
+    }
+}
+
+impl DoorbellSettings {
+    pub fn debounce(&self) -> Duration {
+        Duration::from_millis(self.debounce_ms)
+    }
+
+    pub fn min_interval(&self) -> Duration {
+        Duration::from_secs(self.min_interval_secs)
+    }
+}
+
+fn invalid(message: String) -> Error {
+    Error::ConfigError(message)
+}
+
+/// The `[name]` section an environment override applies to, which has to be
+/// in the config file.
+fn section<'a, T>(section: &'a mut Option<T>, name: &str, var: &str) -> Result<&'a mut T, Error> {
+    section
+        .as_mut()
+        .ok_or_else(|| invalid(format!("{} set without a [{}] section", var, name)))
+}
+
+impl Config {
+    pub fn from_toml(contents: &str) -> Result<Config, Error> {
+        toml::from_str(contents).map_err(|e| invalid(format!("invalid config file: {}", e)))
+    }
+
+    /// Reads `path`, falling back to defaults when `required` is false and the file is missing.
+    pub fn from_file(path: &Path, required: bool) -> Result<Config, Error> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => Config::from_toml(&contents),
+            Err(e) if !required && e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(Config::default())
+            }
+            Err(e) => Err(invalid(format!("cannot read {}: {}", path.display(), e))),
+        }
+    }
+
+    /// Loads `path`, or `doorlock.toml` when present, then applies `DOORLOCK_*`
+    /// environment variables. Validation is left to the caller, after any flags.
+    pub fn load(path: Option<&Path>) -> Result<Config, Error> {
+        let mut config = match path {
+            Some(path) => Config::from_file(path, true)?,
+            None => Config::from_file(Path::new("doorlock.toml"), false)?,
+        };
+        config.apply_env(|name| std::env::var(name).ok())?;
+        Ok(config)
+    }
+
+    /// Applies `DOORLOCK_*` overrides looked up through `var`: the listen
+    /// address, reset URL, doorbell pin and every secret.
+    pub fn apply_env<F>(&mut self, var: F) -> Result<(), Error>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        if let Some(listen) = var("DOORLOCK_LISTEN") {
+            self.listen = listen;
+        }
+        if let Some(secret) = var("DOORLOCK_JWT_SECRET") {
+            self.jwt_secret = secret;
+        }
+        if let Some(reset_url) = var("DOORLOCK_RESET_URL") {
+            self.reset_url = reset_url;
+        }
+        if let Some(setup_token) = var("DOORLOCK_SETUP_TOKEN") {
+            self.setup_token = Some(setup_token);
+        }
+        if let Some(api_key) = var("DOORLOCK_MAILGUN_API_KEY") {
+            section(&mut self.mailgun, "mailgun", "DOORLOCK_MAILGUN_API_KEY")?.api_key = api_key;
+        }
+        if let Some(key_file) = var("DOORLOCK_APNS_KEY_FILE") {
+            section(&mut self.apns, "apns", "DOORLOCK_APNS_KEY_FILE")?.key_file = key_file;
+        }
+        if let Some(file) = var("DOORLOCK_FCM_SERVICE_ACCOUNT_FILE") {
+            section(&mut self.fcm, "fcm", "DOORLOCK_FCM_SERVICE_ACCOUNT_FILE")?
+                .service_account_file = file;
+        }
+        if let Some(token) = var("DOORLOCK_NTFY_TOKEN") {
+            section(&mut self.ntfy, "ntfy", "DOORLOCK_NTFY_TOKEN")?.token = Some(token);
+        }
+        if let Some(app_token) = var("DOORLOCK_GOTIFY_APP_TOKEN") {
+            section(&mut self.gotify, "gotify", "DOORLOCK_GOTIFY_APP_TOKEN")?.app_token = app_token;
+        }
+        if let Some(secret) = var("DOORLOCK_WEBHOOK_SECRET") {
+            section(&mut self.webhook, "webhook", "DOORLOCK_WEBHOOK_SECRET")?.secret = Some(secret);
+        }
+        if let Some(pin) = var("DOORLOCK_DOORBELL_PIN") {
+            self.doorbell.pin = Some(pin.parse()?);
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        self.listen
+            .parse::<SocketAddr>()
+            .map_err(|e| invalid(format!("listen address {}: {}", self.listen, e)))?;
+
+        if self.jwt_secret.len() < 16 {
+            Err(invalid(
+                "jwt_secret must be set and at least 16 characters long".to_string(),
+            ))?
+        }
+        if self.access_token_minutes < 1 || self.refresh_token_days < 1 {
+            Err(invalid(
+                "access_token_minutes and refresh_token_days must be positive".to_string(),
+            ))?
+        }
+        if !(1..=24 * 60).contains(&self.reset_token_minutes) {
+            Err(invalid(
+                "reset_token_minutes must be between 1 and 1440".to_string(),
+            ))?
+        }
+        if !(1..=30 * 24).contains(&self.invite_token_hours) {
+            Err(invalid(
+                "invite_token_hours must be between 1 and 720".to_string(),
+            ))?
+        }
+        if !(0..=100).contains(&self.low_battery_percent) {
+            Err(invalid(
+                "low_battery_percent must be between 0 and 100".to_string(),
+            ))?
+        }
+        if check_locale(&self.locale).ok().as_ref() != Some(&self.locale) {
+            Err(invalid(format!(
+                "locale {} must look like en or de-AT",
+                self.locale
+            )))?
+        }
+        if matches!(&self.setup_token, Some(token) if token.len() < 16) {
+            Err(invalid(
+                "setup_token must be at least 16 characters long".to_string(),
+            ))?
+        }
+        let password = &self.password;
+        if password.iterations < 1 || password.parallelism < 1 {
+            Err(invalid(
+                "password iterations and parallelism must be at least 1".to_string(),
+            ))?
+        }
+        if password.memory_kib < 8 * password.parallelism {
+            Err(invalid(
+                "password memory_kib must be at least 8 times the parallelism".to_string(),
+            ))?
+        }
+        let login = &self.login;
+        if login.base_delay_secs < 1 || login.max_delay_secs < login.base_delay_secs {
+            Err(invalid(
+                "login base_delay_secs must be at least 1 and at most max_delay_secs".to_string(),
+            ))?
+        }
+        if login.lockout_after <= login.free_attempts || login.lockout_minutes < 1 {
+            Err(invalid(
+                "login lockout_after must exceed free_attempts and lockout_minutes be positive"
+                    .to_string(),
+            ))?
+        }
+        if self.notify.max_concurrent < 1 || self.notify.retries > 10 {
+            Err(invalid(
+                "notify max_concurrent must be at least 1 and retries at most 10".to_string(),
+            ))?
+        }
+        if !self.reset_url.starts_with("http://") && !self.reset_url.starts_with("https://") {
+            Err(invalid(format!(
+                "reset_url {} is not an http url",
+                self.reset_url
+            )))?
+        }
+        if let Some(mailgun) = &self.mailgun {
+            if mailgun.domain.is_empty() || mailgun.api_key.is_empty() || mailgun.sender.is_empty()
+            {
+                Err(invalid(
+                    "mailgun needs a domain, api_key and sender".to_string(),
+                ))?
+            }
+        }
+        if let Some(apns) = &self.apns {
+            if apns.team_id.is_empty() || apns.key_id.is_empty() {
+                Err(invalid("apns needs a team_id and key_id".to_string()))?
+            }
+            if !Path::new(&apns.key_file).is_file() {
+                Err(invalid(format!(
+                    "apns key_file {} not found",
+                    apns.key_file
+                )))?
+            }
+        }
+        if let Some(fcm) = &self.fcm {
+            if fcm.project_id.is_empty() {
+                Err(invalid("fcm needs a project_id".to_string()))?
+            }
+            if !Path::new(&fcm.service_account_file).is_file() {
+                Err(invalid(format!(
+                    "fcm service_account_file {} not found",
+                    fcm.service_account_file
+                )))?
+            }
+        }
+        let urls = [
+            self.ntfy.as_ref().map(|ntfy| ("ntfy server", &ntfy.server)),
+            self.gotify
+                .as_ref()
+                .map(|gotify| ("gotify server", &gotify.server)),
+            self.webhook
+                .as_ref()
+                .map(|webhook| ("webhook url", &webhook.url)),
+        ];
+        for (name, url) in urls.iter().flatten() {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                Err(invalid(format!("{} {} is not an http url", name, url)))?
+            }
+        }
+        for door in self.actuators.keys() {
+            door.parse::<i32>()
+                .map_err(|_e| invalid(format!("actuator door id {} is not a number", door)))?;
+        }
+        Ok(())
+    }
+
+    /// Actuators in the `1=servo:17,2=relay:27` form `Actuators::from_spec` reads.
+    pub fn actuator_spec(&self) -> String {
+        self.actuators
+            .iter()
+            .map(|(door, kind)| format!("{}={}", door, kind))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+    use std::collections::HashMap;
+
+    const EXAMPLE: &str = r#"
+        listen = "127.0.0.1:9000"
+        jwt_secret = "0123456789abcdef"
+        reset_url = "https://door.example.com/email"
+
+        [mailgun]
+        domain = "mg.example.com"
+        api_key = "from-file"
+        sender = "postmaster@mg.example.com"
+
+        [webhook]
+        url = "https://homeassistant.local/api/webhook/doorlock"
+
+        [doorbell]
+        pin = 26
+
+        [actuators]
+        1 = "servo:17"
+    "#;
+
+    #[test]
+    fn reads_toml_and_applies_env_overrides() {
+        let mut config = Config::from_toml(EXAMPLE).unwrap();
+        assert_eq!(config.listen, "127.0.0.1:9000");
+        assert_eq!(config.doorbell.pin, Some(26));
+        assert_eq!(config.doorbell.debounce_ms, 100);
+        assert_eq!(config.actuator_spec(), "1=servo:17");
+
+        let env = vec![
+            ("DOORLOCK_LISTEN", "0.0.0.0:80"),
+            ("DOORLOCK_MAILGUN_API_KEY", "from-env"),
+            ("DOORLOCK_WEBHOOK_SECRET", "hook-secret"),
+        ]
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+        config
+            .apply_env(|name| env.get(name).map(|value| value.to_string()))
+            .unwrap();
+
+        assert_eq!(config.listen, "0.0.0.0:80");
+        assert_eq!(config.mailgun.as_ref().unwrap().api_key, "from-env");
+        let secret = config.webhook.as_ref().unwrap().secret.as_deref();
+        assert_eq!(secret, Some("hook-secret"));
+        assert!(config.validate().is_ok());
+
+        let gotify = |name: &str| (name == "DOORLOCK_GOTIFY_APP_TOKEN").then(|| "t".to_string());
+        assert!(config.apply_env(gotify).is_err());
+    }
+
+    #[test]
+    fn rejects_weak_or_unknown_settings() {
+        let mut config = Config::from_toml(EXAMPLE).unwrap();
+        config.jwt_secret = "secret".to_string();
+        assert!(config.validate().is_err());
+
+        assert!(Config::from_toml("jwt_secret = 1").is_err());
+        assert!(Config::from_toml("listn = \"0.0.0.0:80\"").is_err());
+    }
+}
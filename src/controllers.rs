@@ -1,114 +1,206 @@
 use crate::{
-    auth::{create_jwt, Role},
     prisma::{
-        FindFirstUserArgs, UpdateOneUserArgs, User, UserUpdateInput, UserUpdateInputDeviceToken,
-        UserUpdateInputPassword, UserWhereInput, UserWhereInputEmail, UserWhereUniqueInput,
+        FindFirstUserArgs, UpdateOneUserArgs, User, UserUpdateInput,
+        UserUpdateInputMustChangePassword, UserUpdateInputPassword, UserWhereInput,
+        UserWhereInputEmail, UserWhereUniqueInput,
     },
-    DataLoss, FormData, LoginResponse, ResetPassword,
+    DataLoss, FormData, ResetPassword,
 };
-use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
-use mailgun_rs::{EmailAddress, Mailgun, Message};
-use std::{convert::TryInto, sync::Arc};
+use chrono::{Duration, Utc};
+use std::sync::Arc;
 use tide::{Body, Error as TideError, Request, StatusCode};
 
 use crate::{
-    auth::{Claims, JWT_SECRET},
-    error::Error,
+    devices,
+    error::{db_error, Error},
+    events::{self, EventResult, NewEvent},
+    lockout::{self, client_ip},
+    mail,
+    middleware::ClaimsExt,
+    reset, session,
+    templates::{Message, Templates},
+    utils::{check_password, Verification},
     AppleNotifications, LoginRequest, TideState,
 };
 
+/// Registration route of older apps, which know a single device token.
 pub async fn applenotification_handler(mut req: Request<Arc<TideState>>) -> tide::Result {
     let notifications = req.body_json::<AppleNotifications>().await?;
 
-    let token = req
-        .header("Authorization")
-        .map(|token| token.as_str().to_string());
+    let claims = req.claims()?;
+    let state = req.state();
 
-    let token = token
-        .map(|token| token.split("Bearer: ").collect::<Vec<_>>()[1].to_string())
-        .ok_or_else(|| Error::JWTTokenError)?;
-
-    let decoded = decode::<Claims>(
-        &token,
-        &DecodingKey::from_secret(JWT_SECRET),
-        &Validation::new(Algorithm::HS512),
+    let platform = devices::parse_platform(state, notifications.platform.as_deref())?;
+    devices::register(
+        state,
+        claims.sub as i32,
+        platform,
+        notifications.device_token,
+        None,
     )
-    .map_err(|_e| {
-        tide::http::Error::from_str(StatusCode::BadRequest, Error::JWTTokenError.to_string())
-    })?;
-
-    let test_token = req
-        .state()
-        .prisma
-        .update_user::<User>(UpdateOneUserArgs {
-            data: UserUpdateInput {
-                device_token: Some(Some(UserUpdateInputDeviceToken::String(
-                    notifications.device_token,
-                ))),
-                ..Default::default()
-            },
-            filter: UserWhereUniqueInput {
-                email: Some(decoded.claims.email),
-                ..Default::default()
-            },
-        })
-        .await
-        .map_err(|e| TideError::from_str(400, format!("Invalid Token: {}", e)))?;
-    println!("apple token? {:?}", test_token);
-    println!("well");
+    .await?;
 
     Ok(format!("Tester ",).into())
 }
 
-pub async fn login_handler(mut req: Request<Arc<TideState>>) -> tide::Result {
-    let login_request = req.body_json::<LoginRequest>().await?;
+/// Looks up `email` and checks `password` against it, refusing while the
+/// client address or the account has to wait after earlier failures. Unknown
+/// emails take as long as wrong passwords. Refusals are recorded as `action`.
+async fn check_credentials(
+    req: &Request<Arc<TideState>>,
+    action: &str,
+    email: &str,
+    password: &str,
+) -> tide::Result<(User, Verification)> {
+    let state = req.state();
+    let ip = client_ip(req);
+    let now = Utc::now();
+    let denied = |reason: String| {
+        NewEvent::new(action, EventResult::Denied)
+            .source(req)
+            .user(None, Some(email.to_string()))
+            .reason(reason)
+    };
 
-    let password = login_request.pw; // bcrypt
-    let user = req
-        .state()
+    if let Some(ip) = &ip {
+        if let Err(e) = state.ip_throttle.check(ip, now) {
+            events::record(state, denied(format!("address throttled: {}", e))).await;
+            return Err(e.into_tide());
+        }
+    }
+
+    let user = state
         .prisma
         .first_user::<User>(FindFirstUserArgs {
             filter: Some(UserWhereInput {
-                email: Some(UserWhereInputEmail::String(login_request.email)),
+                email: Some(UserWhereInputEmail::String(email.to_string())),
                 ..Default::default()
             }),
             ..Default::default()
         })
         .await
-        .map_err(|_e| TideError::from_str(StatusCode::NotFound, "User not found"))?
-        .ok_or_else(|| Error::JWTTokenError)?; //tide error
-    println!("{}", user.password);
-    let matches = req
-        .state()
+        .map_err(db_error)?;
+    let user = match user {
+        Some(user) => user,
+        None => {
+            state.hasher.verify_nothing(password);
+            if let Some(ip) = &ip {
+                state.ip_throttle.fail(ip, now);
+            }
+            events::record(state, denied("unknown email".to_string())).await;
+            return Err(Error::WrongCredentialsError.into_tide());
+        }
+    };
+
+    // held until a failure is counted, so parallel guesses can't all pass the
+    // lockout check before the first of them is recorded
+    let (_attempt, user) = state.failed_logins.lock(state, &user).await?;
+
+    if let Err(e) = lockout::check_account(&state.config.login, &user, now) {
+        let event = denied(e.to_string()).user(Some(user.id), Some(user.email.clone()));
+        events::record(state, event).await;
+        return Err(e.into_tide());
+    }
+
+    let verification = state
         .hasher
-        .verify(&password, &user.password)
-        .map_err(|e| TideError::from_str(500, format!("Failed to verify password: {}", e)))?;
+        .verify(password, &user.password)
+        .map_err(|e| Error::from(e).into_tide())?;
+    if !verification.matches() {
+        lockout::fail_account(req, &user).await?;
+        if let Some(ip) = &ip {
+            state.ip_throttle.fail(ip, now);
+        }
+        let event = denied("wrong password".to_string()).user(Some(user.id), Some(user.email));
+        events::record(state, event).await;
+        return Err(Error::WrongCredentialsError.into_tide());
+    }
+
+    let user = if user.failed_logins > 0 || user.last_failed_login.is_some() {
+        lockout::reset_account(state, &user).await?
+    } else {
+        user
+    };
+    Ok((user, verification))
+}
+
+pub async fn login_handler(mut req: Request<Arc<TideState>>) -> tide::Result {
+    let login_request = req.body_json::<LoginRequest>().await?;
 
-    if !matches {
+    let password = login_request.pw;
+    let (user, verification) =
+        check_credentials(&req, "login", &login_request.email, &password).await?;
+
+    let event = NewEvent::new("login", EventResult::Denied)
+        .source(&req)
+        .user(Some(user.id), Some(user.email.clone()));
+    if user.disabled {
+        events::record(req.state(), event.reason("account disabled")).await;
         return Err(TideError::from_str(
-            StatusCode::BadRequest,
-            "email or password not correct",
+            StatusCode::Forbidden,
+            "This account has been disabled",
         ));
     }
+    // invited users log in once they replaced their temporary password via /reset
+    if user.must_change_password {
+        events::record(req.state(), event.reason("password change required")).await;
+        return Err(Error::PasswordChangeRequired.into_tide());
+    }
+    let event = NewEvent {
+        result: EventResult::Allowed,
+        ..event
+    };
+    events::record(req.state(), event).await;
 
-    let token = create_jwt(
-        user.id.try_into().unwrap(),
-        &Role::from_str(&user.role),
-        user.email,
-    )
-    .map_err(|e| tide::http::Error::from(e))?;
+    if verification == Verification::NeedsRehash {
+        upgrade_hash(req.state(), &user, &password).await;
+    }
 
-    println!("token: {}", token);
+    let tokens = session::start(&req, &user).await?;
 
     let mut res = tide::Response::new(StatusCode::Accepted);
-    res.set_body(Body::from_json(&LoginResponse { token }).unwrap());
+    res.set_body(Body::from_json(&tokens)?);
 
     Ok(res)
 }
 
+/// Replaces the stored hash of `user` with one made with the current parameters.
+/// Failures are only printed since the login itself already succeeded.
+async fn upgrade_hash(state: &TideState, user: &User, password: &str) {
+    let hashed = match state.hasher.hash(password) {
+        Ok(hashed) => hashed,
+        Err(e) => return println!("Failed to rehash password of {}: {}", user.email, e),
+    };
+
+    let updated = state
+        .prisma
+        .update_user::<User>(UpdateOneUserArgs {
+            data: UserUpdateInput {
+                password: Some(UserUpdateInputPassword::String(hashed)),
+                ..Default::default()
+            },
+            filter: UserWhereUniqueInput {
+                id: Some(user.id),
+                ..Default::default()
+            },
+        })
+        .await;
+    if let Err(e) = updated {
+        println!("Failed to store rehashed password of {}: {}", user.email, e);
+    }
+}
+
+/// Changes a password given the current one. Also how invited users replace
+/// their temporary password before their first login.
 pub async fn reset_handler(mut req: Request<Arc<TideState>>) -> tide::Result {
     let change_password = req.body_json::<FormData>().await?;
-    println!("crazy");
+    check_password(&change_password.new_password).map_err(Error::into_tide)?;
+    if change_password.new_password == change_password.current_password {
+        return Err(Error::InvalidInput(
+            "the new password must differ from the current one".to_string(),
+        )
+        .into_tide());
+    }
     // check token validity
 
     // let token = req
@@ -116,38 +208,16 @@ pub async fn reset_handler(mut req: Request<Arc<TideState>>) -> tide::Result {
     //     .map(|token| token.as_str().to_string());
     // let email = decode_token(token)?;
 
-    // fetch user with id from token
-    let user = req
-        .state()
-        .prisma
-        .first_user::<User>(FindFirstUserArgs {
-            filter: Some(UserWhereInput {
-                email: Some(UserWhereInputEmail::String(change_password.email)),
-                ..Default::default()
-            }),
-            ..Default::default()
-        })
-        .await
-        .map_err(|_e| TideError::from_str(StatusCode::NotFound, "User not found"))?
-        .ok_or_else(|| Error::JWTTokenError)?; //tide error
-    println!("Works");
-    // hash the old password in FormData, compare with the user password from db
-    let matches = req
-        .state()
-        .hasher
-        .verify(&change_password.current_password, &user.password)
-        .map_err(|e| TideError::from_str(300, format!("Failed to verify password: {}", e)))?;
-    println!("Still works");
-
-    if !matches {
-        return Err(TideError::from_str(
-            StatusCode::BadRequest,
-            "email or password not correct",
-        ));
-    }
+    // the current password is guessable here too, so it is throttled like /login
+    let (user, _) = check_credentials(
+        &req,
+        "password_change",
+        &change_password.email,
+        &change_password.current_password,
+    )
+    .await?;
 
     // take the hash of new password and update user in db
-    println!("still working");
     req.state()
         .prisma
         .update_user::<User>(UpdateOneUserArgs {
@@ -156,10 +226,9 @@ pub async fn reset_handler(mut req: Request<Arc<TideState>>) -> tide::Result {
                     req.state()
                         .hasher
                         .hash(&change_password.new_password)
-                        .map_err(|e| {
-                            TideError::from_str(300, format!("Failed to hash password: {}", e))
-                        })?,
+                        .map_err(|e| Error::from(e).into_tide())?,
                 )),
+                must_change_password: Some(UserUpdateInputMustChangePassword::Boolean(false)),
                 ..Default::default()
             },
             filter: UserWhereUniqueInput {
@@ -168,38 +237,19 @@ pub async fn reset_handler(mut req: Request<Arc<TideState>>) -> tide::Result {
             },
         })
         .await
-        .map_err(|e| TideError::from_str(400, format!("Password invalid: {}", e)))?;
-    println!("well");
+        .map_err(db_error)?;
 
     Ok(format!("Hello User ",).into())
 }
 
 pub async fn email_handler(mut req: Request<Arc<TideState>>) -> tide::Result {
     let update_password = req.body_json::<ResetPassword>().await?;
-    println!("Sasageyo!");
 
-    // let token = req
-    //     .header("Authorization")
-    //     .map(|token| token.as_str().to_string());
-
-    // let emails = decode_token(token)?;
+    check_password(&update_password.reset_password).map_err(Error::into_tide)?;
+    let user_id = reset::redeem(req.state(), &update_password.token).await?;
 
     let user = req
         .state()
-        .prisma
-        .first_user::<User>(FindFirstUserArgs {
-            filter: Some(UserWhereInput {
-                email: Some(UserWhereInputEmail::String(update_password.email)),
-                ..Default::default()
-            }),
-            ..Default::default()
-        })
-        .await
-        .map_err(|_e| TideError::from_str(StatusCode::NotFound, "User not found"))?
-        .ok_or_else(|| Error::JWTTokenError)?; //tide error
-    println!("Sassyy");
-
-    req.state()
         .prisma
         .update_user::<User>(UpdateOneUserArgs {
             data: UserUpdateInput {
@@ -207,60 +257,43 @@ pub async fn email_handler(mut req: Request<Arc<TideState>>) -> tide::Result {
                     req.state()
                         .hasher
                         .hash(&update_password.reset_password)
-                        .map_err(|e| {
-                            TideError::from_str(400, format!("Failed to hash password: {}", e))
-                        })?,
+                        .map_err(|e| Error::from(e).into_tide())?,
                 )),
+                must_change_password: Some(UserUpdateInputMustChangePassword::Boolean(false)),
                 ..Default::default()
             },
             filter: UserWhereUniqueInput {
-                email: Some(user.email),
+                id: Some(user_id),
                 ..Default::default()
             },
         })
         .await
-        .map_err(|e| TideError::from_str(400, format!("Password invalid: {}", e)))?;
-    println!("Partss");
+        .map_err(db_error)?;
 
-    Ok(format!("Hello User ",).into())
-}
+    // whoever had the old password may still be logged in
+    session::revoke_all(req.state(), user.id).await?;
 
-pub fn decode_token(token: Option<String>) -> std::result::Result<String, tide::http::Error> { 
-    let token = token.map(|token| token.split("Bearer: ").collect::<Vec<_>>()[1].to_string());
-
-    println!("Common");
-
-    match token {
-        Some(jwt) => {
-            let decoded = decode::<Claims>(
-                &jwt,
-                &DecodingKey::from_secret(JWT_SECRET),
-                &Validation::new(Algorithm::HS512),
-            )
-            .map_err(|_e| {
-                println!("Token Decode Error {:?}", _e);
-                tide::http::Error::from_str(StatusCode::BadRequest, Error::JWTTokenError)
-            })?;
-            if decoded.claims.role != Role::Admin {
-                Err(tide::http::Error::from_str(
-                    StatusCode::BadRequest,
-                    Error::NoPermissionError,
-                ))?
-            } else {
-                Ok(decoded.claims.email)
-            }
-        }
-        None => Err(tide::http::Error::from_str(
-            StatusCode::BadRequest,
-            "Server error",
-        ))?,
-    }
+    let event = NewEvent::new("password_reset", EventResult::Allowed)
+        .source(&req)
+        .user(Some(user.id), Some(user.email));
+    events::record(req.state(), event).await;
 
-    // println!("GAs");
+    Ok(format!("Hello User ",).into())
 }
 
+/// Emails a single-use reset link. Answers the same whether or not the
+/// address belongs to an account, so it cannot be used to find accounts.
 pub async fn forgot_handler(mut req: Request<Arc<TideState>>) -> tide::Result {
     let forgot_password = req.body_json::<DataLoss>().await?;
+
+    let config = &req.state().config;
+    let mailgun = config.mailgun.as_ref().ok_or_else(|| {
+        TideError::from_str(
+            StatusCode::ServiceUnavailable,
+            "Password reset emails are not configured",
+        )
+    })?;
+
     let user = req
         .state()
         .prisma
@@ -272,56 +305,22 @@ pub async fn forgot_handler(mut req: Request<Arc<TideState>>) -> tide::Result {
             ..Default::default()
         })
         .await
-        .ok()
-        .flatten()
-        .ok_or(TideError::from_str(StatusCode::NotFound, "User not found"))?; //tide error
-    println!("Workss");
-
-    let token = create_jwt(
-        user.id as usize,
-        &Role::from_str(&user.role.clone()),
-        user.email.clone(),
-    )
-    .map_err(|e| tide::http::Error::from(e))?;
-    println!("This is my token={}", token);
-
-    let domain = "sandbox3234fec2e6144717bf98ddfca5eb0b81.mailgun.org";
-    let key = "02c914953aae6aef71afd139f07d4a06-02fa25a3-25b8c2b9";
-    let recipient = user.email;
-    let recipient = EmailAddress::address(&recipient);
-    let message = Message {
-        to: vec![recipient],
-        subject: String::from("Change your password here"),
-        text: String::from("Are you ready to change your password"),
-        html: format!("<p><a href=\"http://192.168.100.204:3000/email?token={}\">click to reset password</a></p>", token),
-        ..Default::default()
+        .map_err(db_error)?;
+    let user = match user {
+        Some(user) if !user.disabled => user,
+        _ => return Ok(tide::Response::new(StatusCode::Accepted)),
     };
-    println!("yupp still workss");
 
-    let client = Mailgun {
-        api_key: String::from(key),
-        domain: String::from(domain),
-        message,
-    };
-    let sender = EmailAddress::name_address(
-        "Click to change your password",
-        "postmaster@sandbox3234fec2e6144717bf98ddfca5eb0b81.mailgun.org",
-    );
-
-    match client.send(&sender) {
-        Ok(_) => {
-            println!("successful");
-        }
-        Err(err) => {
-            println!("{}", err);
-        }
-    }
+    let lifetime = Duration::minutes(config.reset_token_minutes);
+    let token = reset::issue(req.state(), &user, lifetime).await?;
 
-    println!("Hehehe");
-
-    let mut res = tide::Response::new(StatusCode::Accepted);
-    res.set_body(Body::from_json(&LoginResponse { token }).unwrap());
-    Ok(res)
+    let message = Message::new("reset_email")
+        .var("user", &user.name)
+        .var("link", format!("{}?token={}", config.reset_url, token))
+        .var("minutes", config.reset_token_minutes);
+    let templates = Templates::load(req.state()).await?;
+    let (sender, email) = templates.email(&user.locale, user.email.clone(), &message);
+    mail::send(mailgun, &sender, email);
 
-    // Ok(format!("Hello User ",).into())
+    Ok(tide::Response::new(StatusCode::Accepted))
 }
@@ -0,0 +1,268 @@
+use crate::{
+    error::{db_error, Error},
+    middleware::ClaimsExt,
+    notify::{Delivery, NotifyError, Platform},
+    prisma::{
+        DeleteManyDeviceArgs, Device, DeviceCreateInput, DeviceOrderByInput, DeviceUpdateInput,
+        DeviceUpdateInputAppVersion, DeviceUpdateInputLastSeen, DeviceUpdateInputPlatform,
+        DeviceWhereInput, DeviceWhereInputToken, DeviceWhereInputUserId, DeviceWhereUniqueInput,
+        FindManyDeviceArgs, SortOrder, UpdateOneDeviceArgs,
+    },
+    RegisterDeviceRequest, TideState,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{collections::HashSet, sync::Arc};
+use tide::{Body, Error as TideError, Request, StatusCode};
+
+/// A registered device as its owner sees it, without the push token.
+#[derive(Serialize)]
+pub struct DeviceResponse {
+    pub id: i32,
+    pub platform: String,
+    pub app_version: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+impl From<Device> for DeviceResponse {
+    fn from(device: Device) -> Self {
+        DeviceResponse {
+            id: device.id,
+            platform: device.platform,
+            app_version: device.app_version,
+            created_at: device.created_at,
+            last_seen: device.last_seen,
+        }
+    }
+}
+
+/// Platform of a registration, `ios` when left out for older apps. Only
+/// platforms with a configured backend are accepted.
+pub fn parse_platform(state: &TideState, platform: Option<&str>) -> tide::Result<Platform> {
+    let platform = match platform {
+        Some(platform) => Platform::from_str(platform).map_err(|_| {
+            Error::InvalidInput(format!("unknown platform {}", platform)).into_tide()
+        })?,
+        None => Platform::Ios,
+    };
+    if !state.notifiers.supports(platform) {
+        return Err(
+            Error::InvalidInput(format!("{} notifications are not configured", platform))
+                .into_tide(),
+        );
+    }
+    Ok(platform)
+}
+
+/// Registers `token` for `user_id`, or marks it as seen when it is already
+/// theirs. A token registered to someone else is refused with 409 until that
+/// account unregisters it, so a leaked token can't redirect their
+/// notifications. Returns whether the device is new.
+pub async fn register(
+    state: &TideState,
+    user_id: i32,
+    platform: Platform,
+    token: String,
+    app_version: Option<String>,
+) -> tide::Result<(Device, bool)> {
+    platform.check_token(&token).map_err(Error::into_tide)?;
+    let now = Utc::now();
+
+    let existing = state
+        .prisma
+        .device::<Device>(DeviceWhereUniqueInput {
+            token: Some(token.clone()),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?;
+
+    let device = match existing {
+        Some(device) if device.user_id != user_id => {
+            return Err(TideError::from_str(
+                StatusCode::Conflict,
+                "This device is registered to another account, sign out there first",
+            ))
+        }
+        Some(device) => state
+            .prisma
+            .update_device::<Device>(UpdateOneDeviceArgs {
+                data: DeviceUpdateInput {
+                    platform: Some(DeviceUpdateInputPlatform::String(platform.to_string())),
+                    app_version: Some(app_version.map(DeviceUpdateInputAppVersion::String)),
+                    last_seen: Some(DeviceUpdateInputLastSeen::DateTime(now)),
+                    ..Default::default()
+                },
+                filter: DeviceWhereUniqueInput {
+                    id: Some(device.id),
+                    ..Default::default()
+                },
+            })
+            .await
+            .map_err(db_error)?,
+        None => {
+            let device = state
+                .prisma
+                .create_device::<Device>(DeviceCreateInput {
+                    user_id,
+                    platform: platform.to_string(),
+                    token,
+                    app_version,
+                    created_at: now,
+                    last_seen: now,
+                })
+                .await
+                .map_err(db_error)?;
+            return Ok((device, true));
+        }
+    };
+    Ok((device, false))
+}
+
+/// Registered devices of `recipients` as (user id, platform, token). Rows with
+/// a platform this version does not know are skipped.
+pub async fn destinations(
+    state: &TideState,
+    recipients: &HashSet<i32>,
+) -> tide::Result<Vec<(i32, Platform, String)>> {
+    let devices = state
+        .prisma
+        .devices::<Device>(FindManyDeviceArgs {
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?
+        .into_iter()
+        .filter(|device| recipients.contains(&device.user_id))
+        .filter_map(|device| {
+            let platform = Platform::from_str(&device.platform).ok()?;
+            Some((device.user_id, platform, device.token))
+        })
+        .collect();
+    Ok(devices)
+}
+
+/// Forgets devices whose push service reported the token as no longer
+/// registered, e.g. after the app was removed.
+pub async fn prune(state: &TideState, deliveries: &[Delivery]) {
+    for delivery in deliveries {
+        let platform = match (delivery.platform, &delivery.result) {
+            (Some(platform), Err(NotifyError::Unregistered)) => platform,
+            _ => continue,
+        };
+        let removed = state
+            .prisma
+            .delete_many_devices(DeleteManyDeviceArgs {
+                filter: Some(DeviceWhereInput {
+                    token: Some(DeviceWhereInputToken::String(delivery.destination.clone())),
+                    ..Default::default()
+                }),
+            })
+            .await;
+        match removed {
+            Ok(_) => println!("Removed unregistered {} device", platform),
+            Err(e) => println!("Failed to remove unregistered device: {}", e),
+        }
+    }
+}
+
+/// Removes all devices of `user_id`, e.g. when the user is deleted.
+pub async fn remove_all(state: &TideState, user_id: i32) -> tide::Result<()> {
+    state
+        .prisma
+        .delete_many_devices(DeleteManyDeviceArgs {
+            filter: Some(DeviceWhereInput {
+                user_id: Some(DeviceWhereInputUserId::Int(user_id)),
+                ..Default::default()
+            }),
+        })
+        .await
+        .map_err(db_error)?;
+    Ok(())
+}
+
+/// Registers the caller's device for notifications. Apps call this on every
+/// start so that `last_seen` and `app_version` stay current.
+pub async fn register_device(mut req: Request<Arc<TideState>>) -> tide::Result {
+    let request = req.body_json::<RegisterDeviceRequest>().await?;
+    let claims = req.claims()?;
+    let state = req.state();
+
+    let platform = parse_platform(state, request.platform.as_deref())?;
+    let (device, created) = register(
+        state,
+        claims.sub as i32,
+        platform,
+        request.token,
+        request.app_version,
+    )
+    .await?;
+
+    let mut res = tide::Response::new(if created {
+        StatusCode::Created
+    } else {
+        StatusCode::Ok
+    });
+    res.set_body(Body::from_json(&DeviceResponse::from(device))?);
+    Ok(res)
+}
+
+pub async fn list_devices(req: Request<Arc<TideState>>) -> tide::Result {
+    let claims = req.claims()?;
+
+    let devices = req
+        .state()
+        .prisma
+        .devices::<Device>(FindManyDeviceArgs {
+            filter: Some(DeviceWhereInput {
+                user_id: Some(DeviceWhereInputUserId::Int(claims.sub as i32)),
+                ..Default::default()
+            }),
+            order_by: Some(vec![DeviceOrderByInput {
+                last_seen: Some(SortOrder::Desc),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?
+        .into_iter()
+        .map(DeviceResponse::from)
+        .collect::<Vec<_>>();
+
+    let mut res = tide::Response::new(StatusCode::Ok);
+    res.set_body(Body::from_json(&devices)?);
+    Ok(res)
+}
+
+/// Stops notifications to one of the caller's devices, e.g. on sign out.
+pub async fn unregister_device(req: Request<Arc<TideState>>) -> tide::Result {
+    let claims = req.claims()?;
+    let id = req.param("device_id")?.parse::<i32>().map_err(|e| {
+        TideError::from_str(StatusCode::BadRequest, format!("Invalid device id: {}", e))
+    })?;
+    let state = req.state();
+
+    let device = state
+        .prisma
+        .device::<Device>(DeviceWhereUniqueInput {
+            id: Some(id),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?
+        .filter(|device| device.user_id as usize == claims.sub)
+        .ok_or_else(|| TideError::from_str(StatusCode::NotFound, "Device not found"))?;
+
+    state
+        .prisma
+        .delete_device::<Device>(DeviceWhereUniqueInput {
+            id: Some(device.id),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?;
+
+    Ok(tide::Response::new(StatusCode::NoContent))
+}
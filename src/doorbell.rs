@@ -0,0 +1,240 @@
+use crate::{
+    error::{db_error, Error},
+    helpers::spawn_notification,
+    hub::HubMessage,
+    preferences::{Subject, Topic},
+    prisma::{
+        DoorbellRing, DoorbellRingCreateInput, DoorbellRingOrderByInput, FindManyDoorbellRingArgs,
+        SortOrder,
+    },
+    templates::Message,
+    TideState,
+};
+use chrono::Utc;
+use serde::Deserialize;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tide::{Body, Error as TideError, Request, StatusCode};
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone)]
+pub struct DoorbellConfig {
+    /// GPIO pin of the button, `None` when only simulated presses are used.
+    pub pin: Option<u8>,
+    pub debounce: Duration,
+    /// Presses closer together than this are stored but not pushed again.
+    pub min_interval: Duration,
+    /// Door the bell belongs to, reported with every ring.
+    pub door_id: Option<i32>,
+    pub message: String,
+}
+
+impl Default for DoorbellConfig {
+    fn default() -> Self {
+        DoorbellConfig {
+            pin: None,
+            debounce: Duration::from_millis(100),
+            min_interval: Duration::from_secs(30),
+            door_id: None,
+            message: "Someone is waiting for you at the door!".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PressSource {
+    Button,
+    Simulated,
+}
+
+impl std::fmt::Display for PressSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let source = match self {
+            PressSource::Button => "button",
+            PressSource::Simulated => "simulated",
+        };
+        write!(f, "{}", source)
+    }
+}
+
+/// Lets through at most one notification per `min_interval`.
+pub struct RateLimiter {
+    min_interval: Duration,
+    last: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(min_interval: Duration) -> Self {
+        RateLimiter {
+            min_interval,
+            last: None,
+        }
+    }
+
+    pub fn allow(&mut self, now: Instant) -> bool {
+        match self.last {
+            Some(last) if now.duration_since(last) < self.min_interval => false,
+            _ => {
+                self.last = Some(now);
+                true
+            }
+        }
+    }
+}
+
+/// Entry point for presses, from the button interrupt or the simulator endpoint.
+pub struct Doorbell {
+    sender: mpsc::Sender<PressSource>,
+    // keeps the interrupt registered for as long as the server runs
+    #[cfg(feature = "gpio")]
+    _button: Option<rust_gpiozero::Debounced>,
+}
+
+impl Doorbell {
+    /// Creates the bell and the receiving end that `run` consumes.
+    pub fn new(config: &DoorbellConfig) -> Result<(Doorbell, mpsc::Receiver<PressSource>), Error> {
+        let (sender, receiver) = mpsc::channel(16);
+
+        #[cfg(feature = "gpio")]
+        let button = match config.pin {
+            Some(pin) => {
+                use rust_gpiozero::{Button, Debounce};
+
+                let mut button = Button::new(pin).debounce(config.debounce);
+                let send_but = sender.clone();
+                button
+                    .when_pressed(move |_| {
+                        println!("Button was pressed!");
+                        // a full queue already holds a ring to notify about
+                        let _ = send_but.try_send(PressSource::Button);
+                    })
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+                Some(button)
+            }
+            None => None,
+        };
+        #[cfg(not(feature = "gpio"))]
+        if config.pin.is_some() {
+            println!("Doorbell pin ignored: built without the gpio feature");
+        }
+
+        let doorbell = Doorbell {
+            sender,
+            #[cfg(feature = "gpio")]
+            _button: button,
+        };
+        Ok((doorbell, receiver))
+    }
+
+    pub fn press(&self, source: PressSource) -> bool {
+        self.sender.try_send(source).is_ok()
+    }
+}
+
+/// Stores every ring, tells live clients, and pushes a notification unless
+/// one went out within the configured interval.
+pub async fn run(
+    state: Arc<TideState>,
+    config: DoorbellConfig,
+    mut presses: mpsc::Receiver<PressSource>,
+) {
+    let mut limiter = RateLimiter::new(config.min_interval);
+
+    while let Some(source) = presses.recv().await {
+        let at = Utc::now();
+        let notify = limiter.allow(Instant::now());
+
+        let ring = state
+            .prisma
+            .create_doorbell_ring::<DoorbellRing>(DoorbellRingCreateInput {
+                created_at: at,
+                door_id: config.door_id,
+                source: source.to_string(),
+                notified: notify,
+            })
+            .await;
+        let ring_id = match ring {
+            Ok(ring) => Some(ring.id),
+            Err(e) => {
+                println!("Failed to store doorbell ring: {}", e);
+                None
+            }
+        };
+
+        state.hub.publish(HubMessage::Doorbell {
+            id: ring_id,
+            door_id: config.door_id,
+            at,
+        });
+
+        if notify {
+            let mut subject = Subject::new(Topic::Doorbell);
+            if let Some(door_id) = config.door_id {
+                subject = subject.door(door_id);
+            }
+            let message = Message::new("doorbell").var("message", &config.message);
+            spawn_notification(state.clone(), subject, message);
+        }
+    }
+}
+
+pub async fn simulate_press(req: Request<Arc<TideState>>) -> tide::Result {
+    if !req.state().doorbell.press(PressSource::Simulated) {
+        return Err(TideError::from_str(
+            StatusCode::TooManyRequests,
+            "Doorbell is busy",
+        ));
+    }
+
+    Ok(tide::Response::new(StatusCode::Accepted))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RingQuery {
+    pub page: Option<u32>,
+    pub per_page: Option<u32>,
+}
+
+pub async fn list_rings(req: Request<Arc<TideState>>) -> tide::Result {
+    let query = req.query::<RingQuery>()?;
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page.unwrap_or(50).min(500).max(1);
+
+    let rings = req
+        .state()
+        .prisma
+        .doorbell_rings::<DoorbellRing>(FindManyDoorbellRingArgs {
+            order_by: Some(vec![DoorbellRingOrderByInput {
+                id: Some(SortOrder::Desc),
+                ..Default::default()
+            }]),
+            skip: Some((u64::from(page - 1) * u64::from(per_page)) as _),
+            take: Some(per_page as _),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?;
+
+    let mut res = tide::Response::new(StatusCode::Ok);
+    res.set_body(Body::from_json(&rings)?);
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimiter;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn rate_limiter_spaces_out_notifications() {
+        let mut limiter = RateLimiter::new(Duration::from_secs(30));
+        let start = Instant::now();
+
+        assert!(limiter.allow(start));
+        assert!(!limiter.allow(start + Duration::from_secs(10)));
+        assert!(limiter.allow(start + Duration::from_secs(31)));
+        assert!(!limiter.allow(start + Duration::from_secs(40)));
+    }
+}
@@ -2,6 +2,7 @@ use crate::prisma;
 use derive_more::From;
 use serde::Serialize;
 use thiserror::Error;
+use tide::{Body, Response, StatusCode};
 
 #[allow(dead_code)]
 #[derive(Error, Debug, From)]
@@ -10,9 +11,9 @@ pub enum Error {
     WrongCredentialsError,
     #[error("jwt token not valid")]
     JWTTokenError,
-    #[error("jwt token not valid")]
-    InternalServerError,
     #[error("internal server error")]
+    InternalServerError,
+    #[error("could not create jwt token")]
     JWTTokenCreationError,
     #[error("no auth header")]
     NoAuthHeaderError,
@@ -30,10 +31,241 @@ pub enum Error {
     Tide(tide::Error),
     #[error("argon error {:?}", _0)]
     Argon(argon2::Error),
+    #[error("actuator error {}", _0)]
+    #[from(ignore)]
+    ActuatorError(String),
+    #[error("config error: {}", _0)]
+    #[from(ignore)]
+    ConfigError(String),
+    #[error("invalid input: {}", _0)]
+    #[from(ignore)]
+    InvalidInput(String),
+    #[error("the password must be changed before logging in")]
+    PasswordChangeRequired,
+    #[error("too many failed logins, retry in {} seconds", _0)]
+    #[from(ignore)]
+    TooManyAttempts(i64),
+    #[error("account is locked, retry in {} seconds", _0)]
+    #[from(ignore)]
+    AccountLocked(i64),
+}
+
+impl Error {
+    /// Status a request failing with this error is answered with.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::WrongCredentialsError
+            | Error::JWTTokenError
+            | Error::NoAuthHeaderError
+            | Error::InavlidAuthHeaderError => StatusCode::Unauthorized,
+            Error::NoPermissionError | Error::PasswordChangeRequired => StatusCode::Forbidden,
+            Error::InvalidInput(_) | Error::ParseError(_) => StatusCode::BadRequest,
+            Error::IoError(e) => io_status(e),
+            Error::Tide(e) => e.status(),
+            Error::ActuatorError(_) => StatusCode::ServiceUnavailable,
+            Error::TooManyAttempts(_) => StatusCode::TooManyRequests,
+            Error::AccountLocked(_) => StatusCode::Locked,
+            Error::InternalServerError
+            | Error::JWTTokenCreationError
+            | Error::Prisma(_)
+            | Error::Argon(_)
+            | Error::ConfigError(_) => StatusCode::InternalServerError,
+        }
+    }
+
+    /// Machine-readable code sent along with the status, stable across releases.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::WrongCredentialsError => "wrong_credentials",
+            Error::JWTTokenError => "invalid_token",
+            Error::InternalServerError => "internal",
+            Error::JWTTokenCreationError => "token_creation_failed",
+            Error::NoAuthHeaderError => "missing_auth_header",
+            Error::InavlidAuthHeaderError => "invalid_auth_header",
+            Error::NoPermissionError => "forbidden",
+            Error::IoError(e) => status_code(io_status(e)),
+            Error::ParseError(_) => "invalid_number",
+            Error::Prisma(_) => "database",
+            Error::Tide(e) => status_code(e.status()),
+            Error::Argon(_) => "password_hash",
+            Error::ActuatorError(_) => "actuator",
+            Error::ConfigError(_) => "config",
+            Error::InvalidInput(_) => "invalid_input",
+            Error::PasswordChangeRequired => "password_change_required",
+            Error::TooManyAttempts(_) => "too_many_attempts",
+            Error::AccountLocked(_) => "account_locked",
+        }
+    }
+
+    /// Seconds the client should wait before trying again, sent as `Retry-After`.
+    pub fn retry_after(&self) -> Option<i64> {
+        match self {
+            Error::TooManyAttempts(secs) | Error::AccountLocked(secs) => Some(*secs),
+            _ => None,
+        }
+    }
+
+    /// Wraps the error for a handler to return, keeping it available to middleware.
+    pub fn into_tide(self) -> tide::Error {
+        let status = self.status();
+        tide::Error::new(status, self)
+    }
 }
 
+/// For `map_err` on database calls inside handlers.
+pub fn db_error(e: prisma::Error) -> tide::Error {
+    Error::Prisma(e).into_tide()
+}
+
+fn io_status(e: &std::io::Error) -> StatusCode {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => StatusCode::NotFound,
+        _ => StatusCode::InternalServerError,
+    }
+}
+
+/// Code for errors raised as a bare status, e.g. `TideError::from_str(404, ..)`.
+fn status_code(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::BadRequest => "bad_request",
+        StatusCode::Unauthorized => "unauthorized",
+        StatusCode::Forbidden => "forbidden",
+        StatusCode::NotFound => "not_found",
+        StatusCode::MethodNotAllowed => "method_not_allowed",
+        StatusCode::Conflict => "conflict",
+        StatusCode::Locked => "locked",
+        StatusCode::UnprocessableEntity => "unprocessable_entity",
+        StatusCode::TooManyRequests => "too_many_requests",
+        StatusCode::ServiceUnavailable => "service_unavailable",
+        status if status.is_client_error() => "bad_request",
+        _ => "internal",
+    }
+}
+
+/// Body of every failed response.
 #[derive(Serialize, Debug)]
-struct ErrorResponse {
-    message: String,
-    status: String,
+pub struct ErrorResponse {
+    pub status: u16,
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<i64>,
+}
+
+impl ErrorResponse {
+    fn new(status: StatusCode, code: &'static str, message: String) -> Self {
+        // details of internal failures go to the log, not to the client
+        let message = if status == StatusCode::InternalServerError {
+            println!("{} {}: {}", status as u16, code, message);
+            String::from("internal server error")
+        } else {
+            message
+        };
+        Self {
+            status: status as u16,
+            code,
+            message,
+            retry_after: None,
+        }
+    }
+
+    /// Describes the error `res` failed with, if it failed at all.
+    fn from_response(res: &Response) -> Option<Self> {
+        let status = res.status();
+        if !status.is_client_error() && !status.is_server_error() {
+            return None;
+        }
+
+        if let Some(error) = res.downcast_error::<Error>() {
+            Some(Self {
+                retry_after: error.retry_after(),
+                ..Self::new(error.status(), error.code(), error.to_string())
+            })
+        } else if let Some(error) = res.downcast_error::<std::io::Error>() {
+            let status = io_status(error);
+            Some(Self::new(status, status_code(status), error.to_string()))
+        } else if let Some(error) = res.error() {
+            let status = error.status();
+            Some(Self::new(status, status_code(status), error.to_string()))
+        } else if res.is_empty().unwrap_or(false) {
+            // e.g. tide's own 404 for unknown routes
+            let message = status.canonical_reason().to_string();
+            Some(Self::new(status, status_code(status), message))
+        } else {
+            None
+        }
+    }
+}
+
+/// Replaces the body of a failed response with an `ErrorResponse`. Used by the
+/// `After` middleware so every route fails the same way.
+pub fn render(mut res: Response) -> Response {
+    if let Some(error) = ErrorResponse::from_response(&res) {
+        res.set_status(error.status);
+        if let Some(secs) = error.retry_after {
+            res.insert_header("Retry-After", secs.to_string());
+        }
+        if let Ok(body) = Body::from_json(&error) {
+            res.set_body(body);
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render, Error};
+    use tide::{Response, StatusCode};
+
+    async fn body(mut res: Response) -> serde_json::Value {
+        let body = res.take_body().into_string().await.unwrap();
+        serde_json::from_str(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn failed_responses_carry_status_and_code() {
+        let mut res = Response::new(StatusCode::InternalServerError);
+        res.set_error(tide::Error::from(Error::NoPermissionError));
+        let res = render(res);
+        assert_eq!(res.status(), StatusCode::Forbidden);
+        assert_eq!(
+            body(res).await,
+            serde_json::json!({"status": 403, "code": "forbidden", "message": "no permission"})
+        );
+
+        let mut res = Response::new(StatusCode::NotFound);
+        res.set_error(tide::Error::from_str(
+            StatusCode::NotFound,
+            "Door not found",
+        ));
+        assert_eq!(
+            body(render(res)).await,
+            serde_json::json!({"status": 404, "code": "not_found", "message": "Door not found"})
+        );
+
+        let res = render(Response::new(StatusCode::NotFound));
+        assert_eq!(body(res).await["code"], "not_found");
+
+        let mut res = Response::new(StatusCode::InternalServerError);
+        res.set_error(tide::Error::from(Error::TooManyAttempts(8)));
+        let res = render(res);
+        assert_eq!(res.status(), StatusCode::TooManyRequests);
+        assert_eq!(res["Retry-After"], "8");
+        assert_eq!(body(res).await["retry_after"], 8);
+    }
+
+    #[tokio::test]
+    async fn internal_details_are_not_sent() {
+        let mut res = Response::new(StatusCode::InternalServerError);
+        res.set_error(tide::Error::from_str(
+            StatusCode::InternalServerError,
+            "no such table: User",
+        ));
+        let body = body(render(res)).await;
+        assert_eq!(body["code"], "internal");
+        assert_eq!(body["message"], "internal server error");
+
+        let res = render(Response::new(StatusCode::Ok));
+        assert_eq!(res.status(), StatusCode::Ok);
+    }
 }
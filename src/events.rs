@@ -0,0 +1,185 @@
+use crate::{
+    error::db_error,
+    hub::HubMessage,
+    lockout::client_ip,
+    prisma::{
+        AccessEvent, AccessEventCreateInput, AccessEventOrderByInput, AccessEventWhereInput,
+        AccessEventWhereInputAction, AccessEventWhereInputCreatedAt, AccessEventWhereInputDoorId,
+        AccessEventWhereInputResult, AccessEventWhereInputUserId, DateTimeFilter,
+        FindManyAccessEventArgs, SortOrder,
+    },
+    TideState,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{fmt, sync::Arc};
+use tide::{Body, Request, StatusCode};
+
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum EventResult {
+    Allowed,
+    Denied,
+}
+
+impl fmt::Display for EventResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let result = match self {
+            EventResult::Allowed => "allowed",
+            EventResult::Denied => "denied",
+        };
+        write!(f, "{}", result)
+    }
+}
+
+/// An access attempt waiting to be written to the audit log.
+#[derive(Debug, Clone)]
+pub struct NewEvent {
+    pub user_id: Option<i32>,
+    pub email: Option<String>,
+    pub door_id: Option<i32>,
+    pub action: String,
+    pub result: EventResult,
+    pub source_ip: Option<String>,
+    pub reason: Option<String>,
+}
+
+impl NewEvent {
+    pub fn new<T: Into<String>>(action: T, result: EventResult) -> Self {
+        NewEvent {
+            user_id: None,
+            email: None,
+            door_id: None,
+            action: action.into(),
+            result,
+            source_ip: None,
+            reason: None,
+        }
+    }
+
+    pub fn source(mut self, req: &Request<Arc<TideState>>) -> Self {
+        self.source_ip = client_ip(req);
+        self
+    }
+
+    pub fn user(mut self, user_id: Option<i32>, email: Option<String>) -> Self {
+        self.user_id = user_id;
+        self.email = email;
+        self
+    }
+
+    pub fn door(mut self, door_id: i32) -> Self {
+        self.door_id = Some(door_id);
+        self
+    }
+
+    pub fn reason<T: ToString>(mut self, reason: T) -> Self {
+        self.reason = Some(reason.to_string());
+        self
+    }
+}
+
+/// Writes `event` to the audit log. Failures are printed rather than returned so
+/// that auditing never changes the outcome of the request being audited.
+pub async fn record(state: &TideState, event: NewEvent) -> Option<AccessEvent> {
+    let created = state
+        .prisma
+        .create_access_event::<AccessEvent>(AccessEventCreateInput {
+            created_at: Utc::now(),
+            user_id: event.user_id,
+            email: event.email,
+            door_id: event.door_id,
+            action: event.action,
+            result: event.result.to_string(),
+            source_ip: event.source_ip,
+            reason: event.reason,
+        })
+        .await;
+
+    match created {
+        Ok(event) => {
+            state.hub.publish(HubMessage::AccessEvent {
+                event: event.clone(),
+            });
+            Some(event)
+        }
+        Err(e) => {
+            println!("Failed to record access event: {}", e);
+            None
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct EventQuery {
+    pub page: Option<u32>,
+    pub per_page: Option<u32>,
+    pub user_id: Option<i32>,
+    pub door_id: Option<i32>,
+    pub action: Option<String>,
+    pub result: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct EventPage {
+    pub page: u32,
+    pub per_page: u32,
+    pub events: Vec<AccessEvent>,
+}
+
+pub async fn list_events(req: Request<Arc<TideState>>) -> tide::Result {
+    let query = req.query::<EventQuery>()?;
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query
+        .per_page
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .min(MAX_PAGE_SIZE)
+        .max(1);
+
+    let created_at = match (query.since, query.until) {
+        (None, None) => None,
+        (since, until) => Some(AccessEventWhereInputCreatedAt::DateTimeFilter(
+            DateTimeFilter {
+                gte: since,
+                lte: until,
+                ..Default::default()
+            },
+        )),
+    };
+
+    let events = req
+        .state()
+        .prisma
+        .access_events::<AccessEvent>(FindManyAccessEventArgs {
+            filter: Some(AccessEventWhereInput {
+                user_id: query.user_id.map(AccessEventWhereInputUserId::Int),
+                door_id: query.door_id.map(AccessEventWhereInputDoorId::Int),
+                action: query.action.map(AccessEventWhereInputAction::String),
+                result: query.result.map(AccessEventWhereInputResult::String),
+                created_at,
+                ..Default::default()
+            }),
+            order_by: Some(vec![AccessEventOrderByInput {
+                id: Some(SortOrder::Desc),
+                ..Default::default()
+            }]),
+            // widened so a huge page number can't overflow
+            skip: Some((u64::from(page - 1) * u64::from(per_page)) as _),
+            take: Some(per_page as _),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?;
+
+    let mut res = tide::Response::new(StatusCode::Ok);
+    res.set_body(Body::from_json(&EventPage {
+        page,
+        per_page,
+        events,
+    })?);
+    Ok(res)
+}
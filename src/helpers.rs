@@ -1,213 +1,396 @@
 use crate::{
     prisma::{
-        Door, DoorUpdateInput, DoorUpdateInputState, DoorWhereUniqueInput, FindFirstUserArgs,
-        FindManyUserArgs, UpdateOneDoorArgs, User, UserWhereInput, UserWhereInputEmail,
+        Door, DoorCreateInput, DoorUpdateManyMutationInput, DoorUpdateManyMutationInputState,
+        DoorUpdateManyMutationInputVersion, DoorWhereInput, DoorWhereInputId,
+        DoorWhereInputVersion, DoorWhereUniqueInput, FindFirstUserArgs, FindManyDoorArgs,
+        UpdateManyDoorArgs, User, UserWhereInput, UserWhereInputEmail,
     },
-    Polling,
+    AdminRequest, DoorAction, NewDoor, Polling,
 };
 // use chrono::Utc;
 // use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 use tide::{Error as TideError, Request, StatusCode};
 use tide_websockets::WebSocketConnection;
 
-pub async fn polling(req: Request<Arc<TideState>>) -> tide::Result {
-    let token = req
-        .header("Authorization")
-        .map(|token| token.as_str().to_string());
+/// Door driven by the legacy `/polling` and `/door` routes.
+pub const DEFAULT_DOOR: i32 = 1;
 
-    let _ = decode_token(token)?;
+pub fn door_id(req: &Request<Arc<TideState>>) -> tide::Result<i32> {
+    req.param("id")?
+        .parse::<i32>()
+        .map_err(|e| TideError::from_str(StatusCode::BadRequest, format!("Invalid door id: {}", e)))
+}
 
-    let door = req
-        .state()
+pub async fn find_door(state: &TideState, id: i32) -> tide::Result<Door> {
+    state
         .prisma
-        .door::<Door>(DoorWhereUniqueInput { id: Some(1) })
+        .door::<Door>(DoorWhereUniqueInput {
+            id: Some(id),
+            ..Default::default()
+        })
         .await
-        .map_err(|_e| {
-            tide::http::Error::from_str(StatusCode::BadRequest, Error::InavlidAuthHeaderError)
-        })?
-        .unwrap();
+        .map_err(db_error)?
+        .ok_or_else(|| TideError::from_str(StatusCode::NotFound, "Door not found"))
+}
+
+pub async fn polling(req: Request<Arc<TideState>>) -> tide::Result {
+    door_response(req, DEFAULT_DOOR).await
+}
+
+pub async fn door_state(req: Request<Arc<TideState>>) -> tide::Result {
+    let id = door_id(&req)?;
+    door_response(req, id).await
+}
+
+async fn door_response(req: Request<Arc<TideState>>, id: i32) -> tide::Result {
+    let claims = req.claims()?;
+    authorize(req.state(), &claims, id, Permission::View).await?;
+
+    let door = find_door(req.state(), id).await?;
 
     println!("doorstate: {:?}", door);
 
     let mut res = tide::Response::new(StatusCode::Accepted);
-    res.set_body(tide::Body::from_json(&Polling { door: door.state }).unwrap());
+    res.set_body(tide::Body::from_json(&Polling::from(door))?);
 
     Ok(res)
 }
 
+pub async fn list_doors(req: Request<Arc<TideState>>) -> tide::Result {
+    let claims = req.claims()?;
 
-pub async fn toggle_door_state(req: Request<Arc<TideState>>) -> tide::Result {
-    // check auth
-    let token = req
-        .header("Authorization")
-        .map(|token| token.as_str().to_string());
-
-    let email = decode_token(token)?;
-
-    let user = req
+    let all_doors = req
         .state()
         .prisma
-        .first_user::<User>(FindFirstUserArgs {
-            filter: Some(UserWhereInput {
-                email: Some(UserWhereInputEmail::String(email.clone())),
-                ..Default::default()
-            }),
+        .doors::<Door>(FindManyDoorArgs {
             ..Default::default()
         })
         .await
-        .map_err(|_e| TideError::from_str(StatusCode::NotFound, "User not found"))?
-        .ok_or_else(|| Error::JWTTokenError)?;
+        .map_err(db_error)?;
+
+    let mut doors = Vec::new();
+    for door in all_doors {
+        if permission_for(req.state(), &claims, door.id)
+            .await?
+            .is_some()
+        {
+            doors.push(Polling::from(door));
+        }
+    }
+
+    let mut res = tide::Response::new(StatusCode::Ok);
+    res.set_body(tide::Body::from_json(&doors)?);
+
+    Ok(res)
+}
+
+pub async fn create_door(mut req: Request<Arc<TideState>>) -> tide::Result {
+    let new_door = req.body_json::<NewDoor>().await?;
+    relock::validate_relock_after(new_door.relock_after)?;
 
-    // first check state of the door
     let door = req
         .state()
         .prisma
-        .door::<Door>(DoorWhereUniqueInput { id: Some(1) })
+        .create_door::<Door>(DoorCreateInput {
+            name: new_door.name,
+            location: new_door.location,
+            state: DoorState::Locked.to_string(),
+            version: 0,
+            relock_after: new_door.relock_after,
+            relock_at: None,
+            battery: None,
+        })
         .await
-        .map_err(|_e| {
-            tide::http::Error::from_str(StatusCode::BadRequest, Error::InavlidAuthHeaderError)
-        })?
-        .unwrap();
+        .map_err(|e| TideError::from_str(400, format!("Door invalid: {}", e)))?;
+
+    let mut res = tide::Response::new(StatusCode::Created);
+    res.set_body(tide::Body::from_json(&Polling::from(door))?);
 
-    println!("Toggle state:{:?}", door);
+    Ok(res)
+}
 
-    let log = format!("{},{},{}", email, chrono::Utc::now(), &door.state);
-    let _ = std::fs::write("log.txt", log.as_bytes());
+pub async fn toggle_door_state(mut req: Request<Arc<TideState>>) -> tide::Result {
+    let request = req.body_json::<AdminRequest>().await?;
+    act(req, DEFAULT_DOOR, Intent::Explicit(request)).await
+}
+
+pub async fn door_action(mut req: Request<Arc<TideState>>) -> tide::Result {
+    let request = req.body_json::<AdminRequest>().await?;
+    let id = door_id(&req)?;
+    act(req, id, Intent::Explicit(request)).await
+}
+
+pub async fn toggle_door(req: Request<Arc<TideState>>) -> tide::Result {
+    let id = door_id(&req)?;
+    act(req, id, Intent::Toggle).await
+}
+
+enum Intent {
+    Explicit(AdminRequest),
+    /// Reverse whatever state was read, failing if another request gets there first.
+    Toggle,
+}
 
-    println!("{}", log);
+async fn act(req: Request<Arc<TideState>>, id: i32, intent: Intent) -> tide::Result {
+    let claims = req.claims()?;
 
-    // put this in state
-    // let servo = &req.state().servo;
-    // let mut servo = servo.lock().await;
+    // first check state of the door
+    let door = match check_access(req.state(), &claims, id).await {
+        Ok(door) => door,
+        Err(e) => {
+            if e.status() == StatusCode::Forbidden {
+                let subject = Subject::new(Topic::FailedAttempts)
+                    .door(id)
+                    .actor(claims.sub as i32);
+                let door = match find_door(req.state(), id).await {
+                    Ok(door) => door.name,
+                    Err(_) => id.to_string(),
+                };
+                let message = Message::new("failed_attempt")
+                    .var("user", &claims.email)
+                    .var("door", door);
+                spawn_notification(req.state().clone(), subject, message);
+            }
+            return Err(deny(&req, &claims, id, e).await);
+        }
+    };
 
-    let door_state = match DoorState::from_str(&door.state).map_err(|_e| {
-        tide::http::Error::from_str(StatusCode::NotAcceptable, Error::NoAuthHeaderError)
-    })? {
-        DoorState::Open => {
-            // servo.min();
-            DoorState::Close
+    // held until the new state is stored, so that of two concurrent actions
+    // only the one passing the version check moves the hardware
+    let mut actuator = match actuator::claim(req.state(), &door).await {
+        Ok(actuator) => actuator,
+        Err(e) => return Err(deny(&req, &claims, id, e).await),
+    };
+    let door = find_door(req.state(), id).await?;
+
+    let current = DoorState::from_str(&door.state).unwrap_or(DoorState::Unknown);
+    let (action, expected_version) = match intent {
+        Intent::Explicit(request) => {
+            let action = DoorAction::from_str(&request.action).map_err(|_e| {
+                TideError::from_str(
+                    StatusCode::BadRequest,
+                    format!("Unknown action: {}", request.action),
+                )
+            })?;
+            (action, request.expected_version)
         }
-        DoorState::Close => {
-            // servo.max();
-            DoorState::Open
+        Intent::Toggle => {
+            let action = DoorAction::toggle(current).ok_or_else(|| {
+                TideError::from_str(
+                    StatusCode::Conflict,
+                    format!("Cannot toggle a door that is {}", current),
+                )
+            })?;
+            (action, Some(door.version))
         }
     };
 
-    let testdoor = req
+    if let Some(expected) = expected_version {
+        if expected != door.version {
+            let e = TideError::from_str(
+                StatusCode::Conflict,
+                format!(
+                    "Door is at version {}, not {}; it is now {}",
+                    door.version, expected, current
+                ),
+            );
+            return Err(deny(&req, &claims, id, e).await);
+        }
+    }
+
+    // already where the caller wants it
+    if current == action.target() {
+        let mut res = tide::Response::new(StatusCode::Ok);
+        res.set_body(tide::Body::from_json(&Polling::from(door))?);
+        return Ok(res);
+    }
+
+    let user = req
         .state()
         .prisma
-        .update_door::<Door>(UpdateOneDoorArgs {
-            data: DoorUpdateInput {
-                state: Some(DoorUpdateInputState::String(door_state.to_string())),
-            },
-            filter: DoorWhereUniqueInput { id: Some(1) },
+        .first_user::<User>(FindFirstUserArgs {
+            filter: Some(UserWhereInput {
+                email: Some(UserWhereInputEmail::String(claims.email.clone())),
+                ..Default::default()
+            }),
+            ..Default::default()
         })
         .await
-        .map_err(|e| TideError::from_str(400, format!("Action invalid: {}", e)))?;
+        .map_err(|_e| TideError::from_str(StatusCode::NotFound, "User not found"))?
+        .ok_or_else(|| Error::JWTTokenError)?;
 
-    println!("Update: {:?}", testdoor);
+    // the new state is only stored and broadcast once the hardware has moved
+    if let Err(e) = actuator::drive(req.state(), &mut actuator, &door, action).await {
+        return Err(deny(&req, &claims, id, e).await);
+    }
+    let updated = match set_door_state(req.state(), &door, action.target()).await {
+        Ok(updated) => updated,
+        Err(e) => return Err(deny(&req, &claims, id, e).await),
+    };
+    drop(actuator);
 
-    let message = match door_state {
-        DoorState::Open => {
-            format!("{} {}ed the door", user.name, door_state)
+    if let DoorAction::Unlock | DoorAction::Open = action {
+        // the door is already open, so this must not fail the request
+        if let Err(e) = relock::schedule(req.state().clone(), &updated).await {
+            println!("Failed to schedule relock of {}: {}", updated.name, e);
         }
-        DoorState::Close => {
-            format!("{} {}d the door", user.name, door_state)
-        }
-    };
+    }
 
-    notification_handler(req.state().clone(), message).await?;
+    let event = NewEvent::new(action.to_string(), EventResult::Allowed)
+        .source(&req)
+        .user(Some(user.id), Some(user.email.clone()))
+        .door(door.id);
+    events::record(req.state(), event).await;
 
-    Ok(format!("Action executed").into())
-}
+    let message = Message::new(format!("door.{}", action))
+        .var("user", &user.name)
+        .var("door", &door.name);
+    let subject = Subject::new(Topic::DoorEvents).door(door.id).actor(user.id);
 
-use a2::{Client, Endpoint, NotificationBuilder, NotificationOptions, PlainNotificationBuilder};
-use argparse::{ArgumentParser, Store, StoreOption, StoreTrue};
+    spawn_notification(req.state().clone(), subject, message);
 
-pub async fn notification_handler(state: Arc<TideState>, message: String) -> tide::Result {
-    // pretty_env_logger::init();
+    let mut res = tide::Response::new(StatusCode::Ok);
+    res.set_body(tide::Body::from_json(&Polling::from(updated))?);
+    Ok(res)
+}
 
-    let users = state
+/// Moves `door` to `new_state` unless it changed since it was read, bumping its version.
+/// Fails with 409 when another writer got there first.
+pub async fn set_door_state(
+    state: &TideState,
+    door: &Door,
+    new_state: DoorState,
+) -> tide::Result<Door> {
+    let updated = state
         .prisma
-        .users::<User>(FindManyUserArgs {
-            ..Default::default()
+        .update_many_doors(UpdateManyDoorArgs {
+            data: DoorUpdateManyMutationInput {
+                state: Some(DoorUpdateManyMutationInputState::String(
+                    new_state.to_string(),
+                )),
+                version: Some(DoorUpdateManyMutationInputVersion::Int(door.version + 1)),
+                ..Default::default()
+            },
+            filter: Some(DoorWhereInput {
+                id: Some(DoorWhereInputId::Int(door.id)),
+                version: Some(DoorWhereInputVersion::Int(door.version)),
+                ..Default::default()
+            }),
         })
         .await
-        .map_err(|e| TideError::from_str(400, format!("Error occured: {}", e)))?;
+        .map_err(|e| TideError::from_str(400, format!("Action invalid: {}", e)))?;
 
-    let tokens = users
-        .into_iter()
-        .filter_map(|user| user.device_token)
-        .collect::<Vec<_>>();
+    if updated.count == 0 {
+        return Err(TideError::from_str(
+            StatusCode::Conflict,
+            format!("{} was changed by someone else, try again", door.name),
+        ));
+    }
 
-    println!("apple token: {:?}", tokens);
+    let door = Door {
+        state: new_state.to_string(),
+        version: door.version + 1,
+        ..door.clone()
+    };
+    state.hub.door_changed(&door);
 
-    for mut device_token in tokens {
-        let mut key_file = "*****.p8".to_string();
-        let mut team_id = "*****".to_string();
-        let mut key_id = "*****".to_string();
-        let mut message = message.clone();
-        let mut sandbox = true;
-        let mut topic: Option<String> = Some("*****".to_string());
+    Ok(door)
+}
 
-        {
-            let mut ap = ArgumentParser::new();
-            ap.set_description("APNs token-based push");
-            ap.refer(&mut key_file)
-                .add_option(&["-p", "--pkcs8"], Store, "Private key PKCS8");
-            ap.refer(&mut team_id)
-                .add_option(&["-t", "--team_id"], Store, "APNs team ID");
-            ap.refer(&mut key_id)
-                .add_option(&["-k", "--key_id"], Store, "APNs key ID");
-            ap.refer(&mut device_token).add_option(
-                &["-d", "--device_token"],
-                Store,
-                "APNs device token",
-            );
-            ap.refer(&mut message)
-                .add_option(&["-m", "--message"], Store, "Notification message");
-            ap.refer(&mut sandbox).add_option(
-                &["-s", "--sandbox"],
-                StoreTrue,
-                "Use the development APNs servers",
-            );
-            ap.refer(&mut topic)
-                .add_option(&["-o", "--topic"], StoreOption, "APNS topic");
-            ap.parse_args_or_exit();
-        }
+async fn check_access(state: &TideState, claims: &Claims, id: i32) -> tide::Result<Door> {
+    authorize(state, claims, id, Permission::Open).await?;
+    let door = find_door(state, id).await?;
+    schedule::enforce(state, claims, &door).await?;
+    Ok(door)
+}
 
-        let mut private_key = std::fs::File::open(key_file).unwrap();
+/// Records a refused door action in the audit log and hands back the error.
+async fn deny(
+    req: &Request<Arc<TideState>>,
+    claims: &Claims,
+    id: i32,
+    error: TideError,
+) -> TideError {
+    let event = NewEvent::new("door", EventResult::Denied)
+        .source(req)
+        .user(Some(claims.sub as i32), Some(claims.email.clone()))
+        .door(id)
+        .reason(&error);
+    events::record(req.state(), event).await;
+    error
+}
 
-        let endpoint = if sandbox {
-            Endpoint::Sandbox
-        } else {
-            Endpoint::Production
-        };
+/// Sends `message` in the background, so that the request causing it neither
+/// waits for delivery nor fails along with it.
+pub fn spawn_notification(state: Arc<TideState>, subject: Subject, message: Message) {
+    let runtime = state.runtime.clone();
+    runtime.spawn(async move {
+        let key = message.key.clone();
+        if let Err(e) = notification_handler(state, subject, message).await {
+            println!("Sending {} notification failed: {}", key, e);
+        }
+    });
+}
 
+/// Pushes `message` to the devices of everyone who wants to hear about
+/// `subject`, each in their own language, and to every notification channel.
+pub async fn notification_handler(
+    state: Arc<TideState>,
+    subject: Subject,
+    message: Message,
+) -> tide::Result {
+    let recipients = preferences::recipients(&state, &subject)
+        .await?
+        .into_iter()
+        .map(|recipient| (recipient.user_id, recipient))
+        .collect::<HashMap<_, _>>();
+    let user_ids = recipients.keys().copied().collect::<HashSet<_>>();
+    let templates = Templates::load(&state).await?;
 
-        let client = Client::token(&mut private_key, key_id, team_id, endpoint).unwrap();
+    let devices = devices::destinations(&state, &user_ids)
+        .await?
+        .into_iter()
+        .filter_map(|(user_id, platform, token)| {
+            let recipient = recipients.get(&user_id)?;
+            let notification =
+                templates.notification(&recipient.locale, recipient.utc_offset, &message);
+            Some((platform, token, notification))
+        })
+        .collect::<Vec<_>>();
+    let broadcast = templates.notification(&state.config.locale, 0, &message);
 
-        let options = NotificationOptions {
-            apns_topic: topic.as_ref().map(|s| &**s),
-            ..Default::default()
+    // the backends' HTTP clients need the tokio runtime
+    let sender = state.clone();
+    let deliveries = state
+        .runtime
+        .spawn(async move { sender.notifiers.send(&devices, &broadcast).await })
+        .await
+        .map_err(|e| TideError::from_str(StatusCode::InternalServerError, e.to_string()))?;
+
+    devices::prune(&state, &deliveries).await;
+    let delivered = deliveries.iter().filter(|d| d.result.is_ok()).count();
+    for delivery in &deliveries {
+        let target = match delivery.platform {
+            Some(platform) => format!("{} device", platform),
+            None => "channel".to_string(),
         };
-
-
-        let mut builder = PlainNotificationBuilder::new(message.as_ref());
-        builder.set_sound("default");
-        builder.set_badge(1u32);
-
-
-        let payload = builder.build(device_token.as_ref(), options);
-
-        let response = client.send(payload).await.map_err(|_e| {
-            println!("{}", _e);
-            tide::http::Error::from_str(StatusCode::BadRequest, Error::InavlidAuthHeaderError)
-        })?;
-
-        println!("apple notification server {:?}", response);
+        match &delivery.result {
+            Ok(()) | Err(NotifyError::Unregistered) => {}
+            Err(e) => println!(
+                "Failed to notify {} after {} attempts: {}",
+                target, delivery.attempts, e
+            ),
+        }
     }
+    println!(
+        "Notified {} of {} destinations",
+        delivered,
+        deliveries.len()
+    );
 
     Ok(format!("Testing ").into())
 }
@@ -341,85 +524,55 @@ pub async fn notification_handler(state: Arc<TideState>, message: String) -> tid
 // }
 
 use crate::{
-    controllers::decode_token, error::Error, Alert, ClaimsToken, DoorState, NotificationMessage,
-    TideState,
+    access::{authorize, permission_for, Permission},
+    actuator,
+    auth::Claims,
+    devices,
+    error::{db_error, Error},
+    events::{self, EventResult, NewEvent},
+    middleware::ClaimsExt,
+    notify::NotifyError,
+    preferences::{self, Subject, Topic},
+    relock, schedule,
+    templates::{Message, Templates},
+    Alert, ClaimsToken, DoorState, NotificationMessage, TideState,
 };
 
 #[cfg(test)]
 mod tests {
     #[tokio::test]
+    #[ignore = "sends a real push; needs an [apns] config and DOORLOCK_TEST_DEVICE_TOKEN"]
     async fn notification_handler() {
         let email = "Nathaniel".to_string();
         let action = crate::DoorState::Open;
 
-        let mut key_file = "*****.p8".to_string();
-        let mut team_id = "*****".to_string();
-        let mut key_id = "****".to_string();
-        let mut device_token =
-            "*****".to_string();
-        let mut message = match action {
+        // sends a real push with the APNs settings from doorlock.toml or DOORLOCK_*
+        let config = crate::config::Config::load(None).unwrap();
+        let apns = config.apns.expect("an [apns] section in the config");
+        let device_token = std::env::var("DOORLOCK_TEST_DEVICE_TOKEN").unwrap();
+        let message = match action {
             crate::DoorState::Open => {
                 format!("{} {}ed the door", email, action)
             }
-            crate::DoorState::Close => {
+            _ => {
                 format!("{} {}ed the door", email, action)
             }
         };
-        let mut sandbox = true;
-        let mut topic: Option<String> = Some("*****".to_string());
-
-        {
-            let mut ap = argparse::ArgumentParser::new();
-            ap.set_description("APNs token-based push");
-            ap.refer(&mut key_file).add_option(
-                &["-p", "--pkcs8"],
-                argparse::Store,
-                "Private key PKCS8",
-            );
-            ap.refer(&mut team_id).add_option(
-                &["-t", "--team_id"],
-                argparse::Store,
-                "APNs team ID",
-            );
-            ap.refer(&mut key_id)
-                .add_option(&["-k", "--key_id"], argparse::Store, "APNs key ID");
-            ap.refer(&mut device_token).add_option(
-                &["-d", "--device_token"],
-                argparse::Store,
-                "APNs device token",
-            );
-            ap.refer(&mut message).add_option(
-                &["-m", "--message"],
-                argparse::Store,
-                "Notification message",
-            );
-            ap.refer(&mut sandbox).add_option(
-                &["-s", "--sandbox"],
-                argparse::StoreTrue,
-                "Use the development APNs servers",
-            );
-            ap.refer(&mut topic).add_option(
-                &["-o", "--topic"],
-                argparse::StoreOption,
-                "APNS topic",
-            );
-            ap.parse_args_or_exit();
-        }
-
 
-        let mut private_key = std::fs::File::open(key_file).unwrap();
+        let mut private_key = std::fs::File::open(&apns.key_file).unwrap();
 
-        let endpoint = if sandbox {
+        let endpoint = if apns.sandbox {
             a2::Endpoint::Sandbox
         } else {
             a2::Endpoint::Production
         };
 
 
-        let client = a2::Client::token(&mut private_key, key_id, team_id, endpoint).unwrap();
+        let client =
+            a2::Client::token(&mut private_key, &apns.key_id, &apns.team_id, endpoint).unwrap();
 
         let options = a2::NotificationOptions {
-            apns_topic: topic.as_ref().map(|s| &**s),
+            apns_topic: apns.topic.as_deref(),
             ..Default::default()
         };
 
@@ -0,0 +1,455 @@
+use crate::{
+    access::{permission_for, Permission},
+    auth::{Claims, Role},
+    error::db_error,
+    middleware::ClaimsExt,
+    prisma::{
+        AccessEvent, AccessEventOrderByInput, AccessEventWhereInput, AccessEventWhereInputId, Door,
+        DoorbellRing, DoorbellRingOrderByInput, DoorbellRingWhereInput, DoorbellRingWhereInputId,
+        FindManyAccessEventArgs, FindManyDoorArgs, FindManyDoorbellRingArgs, IntFilter, SortOrder,
+    },
+    Polling, TideState,
+};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tide::{sse::Sender, Endpoint, Request};
+use tide_websockets::{Message, WebSocket, WebSocketConnection};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Messages pushed to live clients, tagged by `type` in their JSON form.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HubMessage {
+    DoorState {
+        door: Polling,
+    },
+    AccessEvent {
+        event: AccessEvent,
+    },
+    Doorbell {
+        /// The stored ring, unless storing it failed.
+        id: Option<i32>,
+        door_id: Option<i32>,
+        at: DateTime<Utc>,
+    },
+}
+
+/// Fans every published message out to all current subscribers.
+/// Subscribers that fall more than the channel capacity behind skip ahead.
+pub struct Hub {
+    sender: broadcast::Sender<HubMessage>,
+}
+
+impl HubMessage {
+    /// Event name used on the SSE stream, matching the JSON `type` tag.
+    pub fn name(&self) -> &'static str {
+        match self {
+            HubMessage::DoorState { .. } => "door_state",
+            HubMessage::AccessEvent { .. } => "access_event",
+            HubMessage::Doorbell { .. } => "doorbell",
+        }
+    }
+}
+
+impl Hub {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender }
+    }
+
+    pub fn publish(&self, message: HubMessage) {
+        // an error only means nobody is listening right now
+        let _ = self.sender.send(message);
+    }
+
+    pub fn door_changed(&self, door: &Door) {
+        self.publish(HubMessage::DoorState {
+            door: Polling::from(door.clone()),
+        });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<HubMessage> {
+        self.sender.subscribe()
+    }
+}
+
+/// Whether `claims` may see `message`: door updates need view access to the
+/// door, the audit trail is admin only, and everyone hears the doorbell.
+pub async fn visible(state: &TideState, claims: &Claims, message: &HubMessage) -> bool {
+    match message {
+        HubMessage::DoorState { door } => matches!(
+            permission_for(state, claims, door.id).await,
+            Ok(Some(permission)) if permission >= Permission::View
+        ),
+        HubMessage::AccessEvent { .. } => claims.role == Role::Admin,
+        HubMessage::Doorbell { .. } => true,
+    }
+}
+
+pub async fn ws_handler(req: Request<Arc<TideState>>) -> tide::Result {
+    let claims = req.claims()?;
+
+    WebSocket::new(move |req: Request<Arc<TideState>>, conn| {
+        forward(req.state().clone(), claims.clone(), conn)
+    })
+    .call(req)
+    .await
+}
+
+/// How often a websocket is pinged; a peer silent for two of these is dropped.
+const PING_EVERY: Duration = Duration::from_secs(30);
+
+async fn forward(
+    state: Arc<TideState>,
+    claims: Claims,
+    conn: WebSocketConnection,
+) -> tide::Result<()> {
+    let mut receiver = state.hub.subscribe();
+    let mut incoming = conn.clone();
+    // tide's tasks don't run on the tokio runtime the timer has to register with
+    let mut ping = {
+        let _runtime = state.runtime.enter();
+        tokio::time::interval(PING_EVERY)
+    };
+    let mut last_heard = Instant::now();
+
+    loop {
+        tokio::select! {
+            frame = incoming.next() => match frame {
+                // tungstenite answers pings on its own
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => last_heard = Instant::now(),
+            },
+            _ = ping.tick() => {
+                if last_heard.elapsed() > PING_EVERY * 2 {
+                    println!("websocket for {} stopped answering", claims.email);
+                    break;
+                }
+                conn.send(Message::Ping(Vec::new())).await?;
+            }
+            message = receiver.recv() => {
+                let message = match message {
+                    Ok(message) => message,
+                    Err(RecvError::Lagged(skipped)) => {
+                        println!(
+                            "websocket for {} skipped {} messages",
+                            claims.email, skipped
+                        );
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+
+                if visible(&state, &claims, &message).await {
+                    // fails once the client has gone away
+                    conn.send_json(&message).await?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Page size when catching a reconnecting client up.
+const REPLAY_PAGE: i64 = 500;
+
+/// How far a client has got through the stored access events and doorbell
+/// rings, sent as the SSE id `<event id>-<ring id>` on every message.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Cursor {
+    pub event: i32,
+    pub ring: i32,
+}
+
+impl Cursor {
+    pub fn parse(id: &str) -> Option<Cursor> {
+        let (event, ring) = id.split_once('-')?;
+        Some(Cursor {
+            event: event.parse().ok()?,
+            ring: ring.parse().ok()?,
+        })
+    }
+
+    /// Whether `message` is a stored one this cursor has already passed.
+    pub fn covers(&self, message: &HubMessage) -> bool {
+        match message {
+            HubMessage::AccessEvent { event } => event.id <= self.event,
+            HubMessage::Doorbell { id: Some(id), .. } => *id <= self.ring,
+            _ => false,
+        }
+    }
+
+    pub fn advance(&mut self, message: &HubMessage) {
+        match message {
+            HubMessage::AccessEvent { event } => self.event = self.event.max(event.id),
+            HubMessage::Doorbell { id: Some(id), .. } => self.ring = self.ring.max(*id),
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.event, self.ring)
+    }
+}
+
+pub async fn sse_handler(req: Request<Arc<TideState>>) -> tide::Result {
+    let claims = req.claims()?;
+    let resume = req
+        .header("Last-Event-ID")
+        .and_then(|id| Cursor::parse(id.as_str()));
+
+    tide::sse::endpoint(move |req: Request<Arc<TideState>>, sender| {
+        forward_sse(req.state().clone(), claims.clone(), resume, sender)
+    })
+    .call(req)
+    .await
+}
+
+async fn send_sse(sender: &Sender, message: &HubMessage, cursor: Cursor) -> tide::Result<()> {
+    let data = serde_json::to_string(message)?;
+    let id = cursor.to_string();
+    sender.send(message.name(), data, Some(&id)).await?;
+    Ok(())
+}
+
+/// The newest stored access event and doorbell ring.
+async fn latest(state: &TideState) -> tide::Result<Cursor> {
+    let event = state
+        .prisma
+        .access_events::<AccessEvent>(FindManyAccessEventArgs {
+            order_by: Some(vec![AccessEventOrderByInput {
+                id: Some(SortOrder::Desc),
+                ..Default::default()
+            }]),
+            take: Some(1),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?;
+    let ring = state
+        .prisma
+        .doorbell_rings::<DoorbellRing>(FindManyDoorbellRingArgs {
+            order_by: Some(vec![DoorbellRingOrderByInput {
+                id: Some(SortOrder::Desc),
+                ..Default::default()
+            }]),
+            take: Some(1),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?;
+
+    Ok(Cursor {
+        event: event.first().map_or(0, |event| event.id),
+        ring: ring.first().map_or(0, |ring| ring.id),
+    })
+}
+
+async fn events_after(state: &TideState, id: i32) -> tide::Result<Vec<AccessEvent>> {
+    state
+        .prisma
+        .access_events::<AccessEvent>(FindManyAccessEventArgs {
+            filter: Some(AccessEventWhereInput {
+                id: Some(AccessEventWhereInputId::IntFilter(IntFilter {
+                    gt: Some(id),
+                    ..Default::default()
+                })),
+                ..Default::default()
+            }),
+            order_by: Some(vec![AccessEventOrderByInput {
+                id: Some(SortOrder::Asc),
+                ..Default::default()
+            }]),
+            take: Some(REPLAY_PAGE as _),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)
+}
+
+async fn rings_after(state: &TideState, id: i32) -> tide::Result<Vec<DoorbellRing>> {
+    state
+        .prisma
+        .doorbell_rings::<DoorbellRing>(FindManyDoorbellRingArgs {
+            filter: Some(DoorbellRingWhereInput {
+                id: Some(DoorbellRingWhereInputId::IntFilter(IntFilter {
+                    gt: Some(id),
+                    ..Default::default()
+                })),
+                ..Default::default()
+            }),
+            order_by: Some(vec![DoorbellRingOrderByInput {
+                id: Some(SortOrder::Asc),
+                ..Default::default()
+            }]),
+            take: Some(REPLAY_PAGE as _),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)
+}
+
+/// Sends everything stored after `cursor` that `claims` may see, a page at a
+/// time until caught up, and returns where that left the client.
+async fn replay(
+    state: &TideState,
+    claims: &Claims,
+    mut cursor: Cursor,
+    sender: &Sender,
+) -> tide::Result<Cursor> {
+    if claims.role == Role::Admin {
+        loop {
+            let missed = events_after(state, cursor.event).await?;
+            let done = (missed.len() as i64) < REPLAY_PAGE;
+            for event in missed {
+                let message = HubMessage::AccessEvent { event };
+                cursor.advance(&message);
+                send_sse(sender, &message, cursor).await?;
+            }
+            if done {
+                break;
+            }
+        }
+    }
+
+    loop {
+        let missed = rings_after(state, cursor.ring).await?;
+        let done = (missed.len() as i64) < REPLAY_PAGE;
+        for ring in missed {
+            let message = HubMessage::Doorbell {
+                id: Some(ring.id),
+                door_id: ring.door_id,
+                at: ring.created_at,
+            };
+            cursor.advance(&message);
+            if visible(state, claims, &message).await {
+                send_sse(sender, &message, cursor).await?;
+            }
+        }
+        if done {
+            break;
+        }
+    }
+
+    Ok(cursor)
+}
+
+/// Sends the current state of every door `claims` may see.
+async fn send_doors(
+    state: &TideState,
+    claims: &Claims,
+    cursor: Cursor,
+    sender: &Sender,
+) -> tide::Result<()> {
+    let doors = state
+        .prisma
+        .doors::<Door>(FindManyDoorArgs {
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?;
+
+    for door in doors {
+        let message = HubMessage::DoorState {
+            door: Polling::from(door),
+        };
+        if visible(state, claims, &message).await {
+            send_sse(sender, &message, cursor).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn forward_sse(
+    state: Arc<TideState>,
+    claims: Claims,
+    resume: Option<Cursor>,
+    sender: Sender,
+) -> tide::Result<()> {
+    // subscribe before replaying so nothing published meanwhile is lost
+    let mut receiver = state.hub.subscribe();
+
+    // messages up to `replayed` were already sent from the database
+    let mut replayed = match resume {
+        Some(cursor) => replay(&state, &claims, cursor, &sender).await?,
+        None => latest(&state).await?,
+    };
+    let mut cursor = replayed;
+
+    // every client starts with its doors, and with an id to resume from
+    send_doors(&state, &claims, cursor, &sender).await?;
+
+    loop {
+        let message = match receiver.recv().await {
+            Ok(message) => message,
+            Err(RecvError::Lagged(skipped)) => {
+                println!(
+                    "event stream for {} skipped {} messages, replaying",
+                    claims.email, skipped
+                );
+                replayed = replay(&state, &claims, cursor, &sender).await?;
+                cursor = replayed;
+                send_doors(&state, &claims, cursor, &sender).await?;
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        if replayed.covers(&message) {
+            continue;
+        }
+        cursor.advance(&message);
+
+        if visible(&state, &claims, &message).await {
+            // fails once the client has gone away
+            send_sse(&sender, &message, cursor).await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring(id: i32) -> HubMessage {
+        HubMessage::Doorbell {
+            id: Some(id),
+            door_id: None,
+            at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn cursor_round_trips_and_tracks_rings() {
+        let mut cursor = Cursor::parse("12-3").unwrap();
+        assert_eq!(cursor, Cursor { event: 12, ring: 3 });
+        assert!(Cursor::parse("12").is_none());
+        assert!(Cursor::parse("a-3").is_none());
+
+        assert!(cursor.covers(&ring(3)));
+        assert!(!cursor.covers(&ring(4)));
+        cursor.advance(&ring(4));
+        // a late, older ring doesn't move the cursor back
+        cursor.advance(&ring(2));
+        assert_eq!(cursor.to_string(), "12-4");
+
+        let unstored = HubMessage::Doorbell {
+            id: None,
+            door_id: None,
+            at: Utc::now(),
+        };
+        assert!(!cursor.covers(&unstored));
+    }
+}
@@ -0,0 +1,359 @@
+use crate::{
+    config::LoginSettings,
+    error::{db_error, Error},
+    events::{self, EventResult, NewEvent},
+    helpers::spawn_notification,
+    preferences::{Subject, Topic},
+    prisma::{
+        UpdateOneUserArgs, User, UserUpdateInput, UserUpdateInputFailedLogins,
+        UserUpdateInputLastFailedLogin, UserUpdateInputLockedUntil, UserWhereUniqueInput,
+    },
+    templates::Message,
+    TideState,
+};
+use chrono::{DateTime, Duration, Utc};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+use tide::{http::proxies::Forwarded, Request};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+/// Addresses tracked before stale ones are dropped.
+const MAX_TRACKED_ADDRESSES: usize = 10_000;
+
+/// Wait imposed after `failures` failed logins when the first `free` cost
+/// nothing: `base_delay_secs`, doubling with every further failure.
+pub fn backoff(settings: &LoginSettings, failures: u32, free: u32) -> Option<Duration> {
+    if failures < free {
+        return None;
+    }
+    let doublings = (failures - free).min(20);
+    let secs = settings
+        .base_delay_secs
+        .saturating_mul(1u64 << doublings)
+        .min(settings.max_delay_secs);
+    Some(Duration::seconds(secs as i64))
+}
+
+/// Whole seconds until `until`, at least one.
+fn seconds_until(until: DateTime<Utc>, now: DateTime<Utc>) -> i64 {
+    let millis = (until - now).num_milliseconds();
+    ((millis + 999) / 1000).max(1)
+}
+
+/// Address a request came from, without the port so every connection of a
+/// client counts together. `Forwarded` and `X-Forwarded-For` are only believed
+/// on connections from `trusted_proxies`, as anyone else could send a new
+/// address with every request.
+pub fn client_ip(req: &Request<Arc<TideState>>) -> Option<String> {
+    let forwarded = Forwarded::from_headers(req).ok().flatten();
+    let forwarded_for = forwarded
+        .as_ref()
+        .map(Forwarded::forwarded_for)
+        .unwrap_or_default();
+    resolve_client(
+        req.peer_addr()?,
+        &forwarded_for,
+        &req.state().config.trusted_proxies,
+    )
+}
+
+/// Walks the forwarding chain back from the connection's peer while the hop
+/// is a trusted proxy; the first address that is not one is the client.
+/// Entries further left were written by the client itself.
+fn resolve_client(
+    peer: &str,
+    forwarded_for: &[&str],
+    trusted_proxies: &[IpAddr],
+) -> Option<String> {
+    let mut client = match parse_ip(peer) {
+        Some(ip) => ip,
+        None => return Some(peer.to_string()),
+    };
+    for hop in forwarded_for.iter().rev() {
+        if !trusted_proxies.contains(&client) {
+            break;
+        }
+        match parse_ip(hop) {
+            Some(ip) => client = ip,
+            None => break,
+        }
+    }
+    Some(client.to_string())
+}
+
+/// Reads `192.0.2.1`, `192.0.2.1:4711`, `[2001:db8::1]` or `[2001:db8::1]:4711`.
+fn parse_ip(addr: &str) -> Option<IpAddr> {
+    let addr = addr.trim();
+    match addr.parse::<SocketAddr>() {
+        Ok(addr) => Some(addr.ip()),
+        Err(_) => addr
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse()
+            .ok(),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Attempts {
+    failures: u32,
+    last_failure: DateTime<Utc>,
+}
+
+/// Failed logins per client address, across all accounts. Kept in memory:
+/// addresses change too often to be worth storing.
+pub struct IpThrottle {
+    settings: LoginSettings,
+    addresses: Mutex<HashMap<String, Attempts>>,
+}
+
+impl IpThrottle {
+    pub fn new(settings: LoginSettings) -> Self {
+        IpThrottle {
+            settings,
+            addresses: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn forget_after(&self) -> Duration {
+        Duration::minutes(self.settings.lockout_minutes)
+    }
+
+    /// Fails with `TooManyAttempts` while `ip` has to wait before trying again.
+    pub fn check(&self, ip: &str, now: DateTime<Utc>) -> Result<(), Error> {
+        let mut addresses = self.addresses.lock().unwrap();
+        let attempts = match addresses.get(ip) {
+            Some(attempts) => *attempts,
+            None => return Ok(()),
+        };
+        if now - attempts.last_failure > self.forget_after() {
+            addresses.remove(ip);
+            return Ok(());
+        }
+
+        let delay = backoff(
+            &self.settings,
+            attempts.failures,
+            self.settings.ip_free_attempts,
+        );
+        match delay {
+            Some(delay) if now < attempts.last_failure + delay => Err(Error::TooManyAttempts(
+                seconds_until(attempts.last_failure + delay, now),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn fail(&self, ip: &str, now: DateTime<Utc>) {
+        let mut addresses = self.addresses.lock().unwrap();
+        if addresses.len() >= MAX_TRACKED_ADDRESSES {
+            let forget_after = self.forget_after();
+            addresses.retain(|_, attempts| now - attempts.last_failure <= forget_after);
+        }
+
+        let attempts = addresses.entry(ip.to_string()).or_insert(Attempts {
+            failures: 0,
+            last_failure: now,
+        });
+        attempts.failures += 1;
+        attempts.last_failure = now;
+    }
+}
+
+/// One lock per account. A login holds it from the lockout check until its
+/// failure is counted, so parallel guesses are judged one after another.
+#[derive(Default)]
+pub struct AccountLocks {
+    accounts: Mutex<HashMap<i32, Arc<AsyncMutex<()>>>>,
+}
+
+impl AccountLocks {
+    /// Waits for the lock of `user` and reads the account again under it.
+    pub async fn lock(
+        &self,
+        state: &TideState,
+        user: &User,
+    ) -> tide::Result<(OwnedMutexGuard<()>, User)> {
+        let account = self
+            .accounts
+            .lock()
+            .unwrap()
+            .entry(user.id)
+            .or_insert_with(|| Arc::new(AsyncMutex::new(())))
+            .clone();
+        let guard = account.lock_owned().await;
+
+        let user = state
+            .prisma
+            .user::<User>(UserWhereUniqueInput {
+                id: Some(user.id),
+                ..Default::default()
+            })
+            .await
+            .map_err(db_error)?
+            .ok_or_else(|| Error::WrongCredentialsError.into_tide())?;
+        Ok((guard, user))
+    }
+}
+
+/// Fails while `user` is locked or still has to wait after recent failures.
+/// Checked before the password so that waiting cannot be skipped by guessing.
+pub fn check_account(
+    settings: &LoginSettings,
+    user: &User,
+    now: DateTime<Utc>,
+) -> Result<(), Error> {
+    if let Some(locked_until) = user.locked_until {
+        if locked_until > now {
+            return Err(Error::AccountLocked(seconds_until(locked_until, now)));
+        }
+    }
+
+    let delay = backoff(settings, user.failed_logins as u32, settings.free_attempts);
+    match (delay, user.last_failed_login) {
+        (Some(delay), Some(last_failure)) if now < last_failure + delay => Err(
+            Error::TooManyAttempts(seconds_until(last_failure + delay, now)),
+        ),
+        _ => Ok(()),
+    }
+}
+
+async fn update_attempts(
+    state: &TideState,
+    user_id: i32,
+    failed_logins: i32,
+    last_failed_login: Option<DateTime<Utc>>,
+    locked_until: Option<DateTime<Utc>>,
+) -> tide::Result<User> {
+    state
+        .prisma
+        .update_user::<User>(UpdateOneUserArgs {
+            data: UserUpdateInput {
+                failed_logins: Some(UserUpdateInputFailedLogins::Int(failed_logins)),
+                last_failed_login: Some(
+                    last_failed_login.map(UserUpdateInputLastFailedLogin::DateTime),
+                ),
+                locked_until: Some(locked_until.map(UserUpdateInputLockedUntil::DateTime)),
+                ..Default::default()
+            },
+            filter: UserWhereUniqueInput {
+                id: Some(user_id),
+                ..Default::default()
+            },
+        })
+        .await
+        .map_err(db_error)
+}
+
+/// Counts a wrong password against `user`, locking the account once it
+/// reaches `lockout_after` failures. The count starts over after a lockout.
+/// `user` must have been read under its `AccountLocks` lock, still held.
+pub async fn fail_account(req: &Request<Arc<TideState>>, user: &User) -> tide::Result<()> {
+    let state = req.state();
+    let settings = &state.config.login;
+    let now = Utc::now();
+    let failures = user.failed_logins + 1;
+
+    if failures < settings.lockout_after as i32 {
+        update_attempts(state, user.id, failures, Some(now), None).await?;
+        return Ok(());
+    }
+
+    let locked_until = now + Duration::minutes(settings.lockout_minutes);
+    update_attempts(state, user.id, 0, None, Some(locked_until)).await?;
+
+    let event = NewEvent::new("account_locked", EventResult::Denied)
+        .source(req)
+        .user(Some(user.id), Some(user.email.clone()))
+        .reason(format!(
+            "{} failed logins, locked until {}",
+            failures,
+            locked_until.to_rfc3339()
+        ));
+    events::record(state, event).await;
+
+    let subject = Subject::new(Topic::FailedAttempts).actor(user.id);
+    let message = Message::new("account_locked")
+        .var("user", &user.email)
+        .var("failures", failures);
+    spawn_notification(state.clone(), subject, message);
+    Ok(())
+}
+
+/// Clears failures and any lockout, after a successful login or by an admin.
+pub async fn reset_account(state: &TideState, user: &User) -> tide::Result<User> {
+    update_attempts(state, user.id, 0, None, None).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{backoff, resolve_client, IpThrottle};
+    use crate::{config::LoginSettings, error::Error};
+    use chrono::{Duration, Utc};
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let settings = LoginSettings::default();
+        assert_eq!(backoff(&settings, 2, 3), None);
+        assert_eq!(backoff(&settings, 3, 3), Some(Duration::seconds(1)));
+        assert_eq!(backoff(&settings, 4, 3), Some(Duration::seconds(2)));
+        assert_eq!(backoff(&settings, 6, 3), Some(Duration::seconds(8)));
+        assert_eq!(backoff(&settings, 200, 3), Some(Duration::seconds(300)));
+    }
+
+    #[test]
+    fn addresses_wait_after_too_many_failures_and_are_forgotten() {
+        let settings = LoginSettings {
+            ip_free_attempts: 2,
+            ..LoginSettings::default()
+        };
+        let throttle = IpThrottle::new(settings);
+        let now = Utc::now();
+
+        throttle.fail("10.0.0.1", now);
+        assert!(throttle.check("10.0.0.1", now).is_ok());
+        throttle.fail("10.0.0.1", now);
+        assert!(matches!(
+            throttle.check("10.0.0.1", now),
+            Err(Error::TooManyAttempts(1))
+        ));
+        assert!(throttle.check("10.0.0.2", now).is_ok());
+        assert!(throttle
+            .check("10.0.0.1", now + Duration::seconds(1))
+            .is_ok());
+
+        throttle.fail("10.0.0.1", now);
+        assert!(throttle.check("10.0.0.1", now + Duration::hours(1)).is_ok());
+        throttle.fail("10.0.0.1", now + Duration::hours(1));
+        assert!(throttle.check("10.0.0.1", now + Duration::hours(1)).is_ok());
+    }
+
+    #[test]
+    fn forwarded_addresses_are_only_taken_from_trusted_proxies() {
+        let proxies = vec!["10.0.0.1".parse().unwrap()];
+        let client = |peer, forwarded: &[&str]| resolve_client(peer, forwarded, &proxies);
+
+        assert_eq!(client("192.0.2.7:4711", &[]).unwrap(), "192.0.2.7");
+        assert_eq!(
+            client("192.0.2.7:4711", &["203.0.113.9"]).unwrap(),
+            "192.0.2.7"
+        );
+        assert_eq!(
+            client("10.0.0.1:4711", &["203.0.113.9"]).unwrap(),
+            "203.0.113.9"
+        );
+        // whatever the client sent ahead of its real address is ignored
+        assert_eq!(
+            client("10.0.0.1:4711", &["198.51.100.1", "203.0.113.9"]).unwrap(),
+            "203.0.113.9"
+        );
+        assert_eq!(
+            client("10.0.0.1:4711", &["[2001:db8::1]:80"]).unwrap(),
+            "2001:db8::1"
+        );
+        assert_eq!(client("10.0.0.1:4711", &["unknown"]).unwrap(), "10.0.0.1");
+    }
+}
@@ -0,0 +1,32 @@
+use crate::config::MailgunConfig;
+use mailgun_rs::{EmailAddress, Mailgun, Message};
+
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+/// Sends `email` through Mailgun, naming the sender `sender_name`. Failures are
+/// only printed: callers answer the same whether or not the mail went out.
+pub fn send(mailgun: &MailgunConfig, sender_name: &str, email: Email) {
+    let message = Message {
+        to: vec![EmailAddress::address(&email.to)],
+        subject: email.subject,
+        text: email.text,
+        html: email.html,
+        ..Default::default()
+    };
+
+    let client = Mailgun {
+        api_key: mailgun.api_key.clone(),
+        domain: mailgun.domain.clone(),
+        message,
+    };
+    let sender = EmailAddress::name_address(sender_name, &mailgun.sender);
+
+    if let Err(err) = client.send(&sender) {
+        println!("Failed to send email to {}: {}", email.to, err);
+    }
+}
@@ -1,28 +1,66 @@
 extern crate mailgun_rs;
 use std::sync::Arc;
 
-use crate::helpers::{notification_handler, polling, toggle_door_state};
-// use futures::StreamExt;
-use prisma::{
-    Door, DoorCreateInput, DoorWhereInput, DoorWhereInputId, FindFirstDoorArgs, FindFirstUserArgs,
-    Prisma, User, UserCreateInput, UserWhereInput, UserWhereInputEmail,
+use crate::helpers::{
+    create_door, door_action, door_state, list_doors, polling, toggle_door, toggle_door_state,
 };
-use prisma_client::futures::lock::Mutex;
-// use rust_gpiozero::{Button, Debounce, Servo};
+// use futures::StreamExt;
+use prisma::{Door, DoorCreateInput, FindFirstDoorArgs, Prisma};
 // use serde::{Deserialize, Serialize};
 // use std::sync::Arc;
 use tide::security::{CorsMiddleware, Origin};
-use tide::{utils::After, Response, StatusCode};
+use tide::{utils::After, Response};
 use utils::Hasher;
 use serde::{Deserialize, Serialize};
 
+mod access;
+mod actuator;
 mod auth;
+mod battery;
+mod cli;
+mod config;
 pub mod controllers;
+mod devices;
+mod doorbell;
 mod error;
+mod events;
 pub mod helpers;
+mod hub;
+mod lockout;
+mod mail;
+mod middleware;
+mod notify;
+mod preferences;
 mod prisma;
+mod relock;
+mod reset;
+mod schedule;
+mod session;
+mod setup;
+mod templates;
+mod users;
 mod utils;
+use access::{list_grants, put_grant, revoke_grant};
+use actuator::Actuators;
+use battery::report_battery;
+use clap::Parser;
+use cli::{Cli, Command};
+use config::Config;
 use controllers::*;
+use devices::{list_devices, register_device, unregister_device};
+use doorbell::{list_rings, simulate_press, Doorbell, DoorbellConfig};
+use events::list_events;
+use hub::{sse_handler, ws_handler, Hub};
+use lockout::{AccountLocks, IpThrottle};
+use middleware::Auth;
+use notify::Notifiers;
+use preferences::{get_preferences, put_preferences};
+use relock::set_relock;
+use schedule::{create_schedule, delete_schedule, list_schedules};
+use session::{list_sessions, logout_handler, refresh_handler, revoke_session};
+use setup::{setup_handler, Setup};
+use templates::{delete_template, list_templates, put_template};
+use users::{create_user, delete_user, list_users, unlock_user, update_user};
 
 #[derive(Deserialize, Serialize)]
 pub struct ClaimsToken {
@@ -36,14 +74,56 @@ pub struct DataLoss {
     pub email: String,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Polling {
+    pub id: i32,
+    pub name: String,
+    pub location: Option<String>,
     pub door: String,
+    pub version: i32,
+    pub relock_after: Option<i32>,
+    pub relock_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Percent left as last reported, `None` for doors that do not report it.
+    pub battery: Option<i32>,
+}
+
+impl From<Door> for Polling {
+    fn from(door: Door) -> Self {
+        Polling {
+            id: door.id,
+            name: door.name,
+            location: door.location,
+            door: door.state,
+            version: door.version,
+            relock_after: door.relock_after,
+            relock_at: door.relock_at,
+            battery: door.battery,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct NewDoor {
+    pub name: String,
+    pub location: Option<String>,
+    pub relock_after: Option<i32>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RelockRequest {
+    /// Seconds after an unlock before the door locks itself, `None` to disable.
+    pub relock_after: Option<i32>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct BatteryRequest {
+    pub percent: i32,
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct ResetPassword {
-    pub email: String,
+    /// Token from the emailed reset link.
+    pub token: String,
     pub reset_password: String,
 }
 
@@ -52,6 +132,54 @@ pub struct Name {
     pub name: String,
 }
 
+#[derive(Deserialize, Serialize)]
+pub struct GrantRequest {
+    pub user_id: i32,
+    pub permission: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ScheduleRequest {
+    pub user_id: i32,
+    pub weekdays: Option<String>,
+    pub date: Option<String>,
+    pub start_time: String,
+    pub end_time: String,
+    /// Minutes east of UTC; prefer `time_zone`, which follows daylight saving.
+    pub utc_offset: Option<i32>,
+    /// An IANA zone such as `Europe/Berlin`.
+    pub time_zone: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct CreateUserRequest {
+    pub email: String,
+    pub name: String,
+    /// `user` when left out.
+    pub role: Option<String>,
+    /// Temporary password handed over in person. Without one an invite is emailed.
+    pub password: Option<String>,
+    /// `locale` from the config when left out.
+    pub locale: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct SetupRequest {
+    /// Printed on startup, or `setup_token` from the config.
+    pub token: String,
+    pub email: String,
+    pub name: String,
+    pub password: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct UpdateUserRequest {
+    pub name: Option<String>,
+    pub role: Option<String>,
+    pub disabled: Option<bool>,
+    pub locale: Option<String>,
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct FormData {
     pub email: String,
@@ -68,16 +196,57 @@ pub struct LoginRequest {
 #[derive(Deserialize, Serialize)]
 pub struct AdminRequest {
     pub action: String,
+    /// When set, the action is refused if the door has changed since this version.
+    pub expected_version: Option<i32>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct LoginResponse {
     pub token: String,
+    pub refresh_token: String,
+    /// Seconds until `token` expires.
+    pub expires_in: i64,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct AppleNotifications {
     pub device_token: String,
+    /// `ios`, `android` or `ntfy`; `ios` when left out.
+    pub platform: Option<String>,
+}
+
+/// Notification settings of the caller; see `preferences::Preferences`.
+#[derive(Serialize, Deserialize)]
+pub struct PreferencesRequest {
+    pub door_events: Option<bool>,
+    pub doorbell: Option<bool>,
+    pub failed_attempts: Option<bool>,
+    pub low_battery: Option<bool>,
+    pub own_actions: Option<bool>,
+    pub door_ids: Option<Vec<i32>>,
+    pub quiet_start: Option<String>,
+    pub quiet_end: Option<String>,
+    pub utc_offset: Option<i32>,
+    /// Language of notifications and emails, kept when left out.
+    pub locale: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TemplateRequest {
+    pub body: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RegisterDeviceRequest {
+    pub token: String,
+    /// `ios`, `android` or `ntfy`; `ios` when left out.
+    pub platform: Option<String>,
+    pub app_version: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -95,21 +264,43 @@ pub type Result<T> = std::result::Result<T, error::Error>;
 pub struct TideState {
     pub prisma: Prisma,
     // counter: std::sync::Arc<std::sync::Mutex<u16>>,
-    // pub servo: std::sync::Arc<Mutex<Servo>>,
+    pub actuators: Actuators,
     pub hasher: Hasher,
+    pub config: Config,
+    /// Handle for background work started from request handlers, which tide
+    /// does not run on the tokio runtime.
+    pub runtime: tokio::runtime::Handle,
+    pub hub: Hub,
+    pub doorbell: Doorbell,
+    pub setup: Setup,
+    /// Failed logins per client address; failures per account are stored with the user.
+    pub ip_throttle: IpThrottle,
+    /// Serializes login attempts per account, so that parallel wrong guesses
+    /// each add to the stored count and none slip past a lockout.
+    pub failed_logins: AccountLocks,
+    /// Push backends by device platform, plus channels notified of everything.
+    pub notifiers: Notifiers,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum DoorState {
+    Locked,
+    Unlocked,
     Open,
-    Close,
+    Closed,
+    Jammed,
+    Unknown,
 }
 
 impl core::fmt::Display for DoorState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let state = match self {
+            DoorState::Locked => "locked",
+            DoorState::Unlocked => "unlocked",
             DoorState::Open => "open",
-            DoorState::Close => "close",
+            DoorState::Closed => "closed",
+            DoorState::Jammed => "jammed",
+            DoorState::Unknown => "unknown",
         };
         write!(f, "{}", state)
     }
@@ -118,17 +309,71 @@ impl core::fmt::Display for DoorState {
 impl DoorState {
     pub fn from_str(action: &str) -> std::result::Result<DoorState, ()> {
         let state = match action.to_lowercase().as_str() {
+            "locked" => DoorState::Locked,
+            "unlocked" => DoorState::Unlocked,
             "open" => DoorState::Open,
-            "close" => DoorState::Close,
+            // "close" is what doors were stored as before explicit actions
+            "closed" | "close" => DoorState::Closed,
+            "jammed" => DoorState::Jammed,
+            "unknown" => DoorState::Unknown,
             _ => Err(())?,
         };
         Ok(state)
     }
 }
 
-#[derive(Clone, Serialize, Deserialize)]
-pub struct TransactionResponse {
-    pub create_user: User,
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DoorAction {
+    Lock,
+    Unlock,
+    Open,
+    Close,
+}
+
+impl DoorAction {
+    pub fn from_str(action: &str) -> std::result::Result<DoorAction, ()> {
+        let action = match action.to_lowercase().as_str() {
+            "lock" => DoorAction::Lock,
+            "unlock" => DoorAction::Unlock,
+            "open" => DoorAction::Open,
+            "close" => DoorAction::Close,
+            _ => Err(())?,
+        };
+        Ok(action)
+    }
+
+    /// State the door is left in once the action succeeds.
+    pub fn target(&self) -> DoorState {
+        match self {
+            DoorAction::Lock => DoorState::Locked,
+            DoorAction::Unlock => DoorState::Unlocked,
+            DoorAction::Open => DoorState::Open,
+            DoorAction::Close => DoorState::Closed,
+        }
+    }
+
+    /// Action that reverses `state`, for the legacy toggle routes.
+    pub fn toggle(state: DoorState) -> Option<DoorAction> {
+        match state {
+            DoorState::Locked => Some(DoorAction::Unlock),
+            DoorState::Unlocked => Some(DoorAction::Lock),
+            DoorState::Open => Some(DoorAction::Close),
+            DoorState::Closed => Some(DoorAction::Open),
+            DoorState::Jammed | DoorState::Unknown => None,
+        }
+    }
+}
+
+impl core::fmt::Display for DoorAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let action = match self {
+            DoorAction::Lock => "lock",
+            DoorAction::Unlock => "unlock",
+            DoorAction::Open => "open",
+            DoorAction::Close => "close",
+        };
+        write!(f, "{}", action)
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -145,51 +390,80 @@ let url = "wss://ws.test.azero.dev";
 
 
     env_logger::init();
+    let cli = Cli::parse();
+    let mut config = Config::load(cli.config.as_deref())?;
+
+    match cli.command.unwrap_or_else(|| Command::Serve(Default::default())) {
+        Command::Serve(args) => {
+            args.apply(&mut config);
+            config.validate()?;
+            serve(config).await
+        }
+        Command::Migrate { schema } => cli::migrate(&schema),
+        Command::Admin(command) => {
+            config.validate()?;
+            let (state, _doorbell_config, _presses) = build_state(config, false).await?;
+            cli::run(state, command).await
+        }
+    }
+}
+
+/// Connects to the database and, with `hardware`, sets up the actuators and
+/// the doorbell button. Also returns what `doorbell::run` needs to start
+/// listening for presses.
+async fn build_state(
+    config: Config,
+    hardware: bool,
+) -> Result<(
+    Arc<TideState>,
+    DoorbellConfig,
+    tokio::sync::mpsc::Receiver<doorbell::PressSource>,
+)> {
     let prisma = Prisma::new(vec![]).await?;
-    let hasher = Hasher::new();
+    let hasher = Hasher::new(&config.password);
+    let ip_throttle = IpThrottle::new(config.login.clone());
+    let notifiers = Notifiers::from_config(&config)?;
+    // admin commands must not claim GPIO pins a running server holds
+    let actuators = if hardware {
+        Actuators::from_spec(&config.actuator_spec())?
+    } else {
+        Actuators::default()
+    };
+    // without a pin the bell only rings through /doorbell/press
+    let doorbell_config = DoorbellConfig {
+        pin: config.doorbell.pin.filter(|_| hardware),
+        debounce: config.doorbell.debounce(),
+        min_interval: config.doorbell.min_interval(),
+        door_id: config.doorbell.door_id,
+        message: config.doorbell.message.clone(),
+    };
+    let (doorbell, presses) = Doorbell::new(&doorbell_config)?;
     let state = Arc::new(TideState {
         prisma,
-        // servo: std::sync::Arc::new(Mutex::new(Servo::new(17))),
+        actuators,
         hasher,
+        config,
+        runtime: tokio::runtime::Handle::current(),
+        hub: Hub::new(64),
+        doorbell,
+        setup: Setup::default(),
+        ip_throttle,
+        failed_logins: AccountLocks::default(),
+        notifiers,
     });
 
-    let message = format!("Someone is waiting for you at the door!");
-
-    // let mut button = Button::new(26).debounce(std::time::Duration::from_millis(100));
-
-    // let (send_but, mut recv_but) = tokio::sync::mpsc::channel(16);
-
-    // button.when_pressed(move|_| {
-    //     println!("Button was pressed!");
-    //     send_but.try_send(()).unwrap();
-    // }).unwrap();
-
-    let state_clone = state.clone();
-
-    // let task = tokio::spawn(async move {
-    //     while let Some(_) = recv_but.recv().await {
-    //         notification_handler(state_clone.clone(), message.clone())
-    //             .await
-    //             .unwrap();
-    //     }
-    // });
+    Ok((state, doorbell_config, presses))
+}
 
-    // button.when_released(|_| {
-    //     println!("Pressed was button.");
-    // });
+async fn serve(config: Config) -> Result<()> {
+    let (state, doorbell_config, presses) = build_state(config, true).await?;
 
-    // button.when_pressed(|_| {
-    //     println!("Push button was pressed!");
-    // });
+    tokio::spawn(doorbell::run(state.clone(), doorbell_config, presses));
 
-    // create door
+    // create a default door on an empty database
     let door_exist = state
         .prisma
         .first_door::<Door>(FindFirstDoorArgs {
-            filter: Some(DoorWhereInput {
-                id: Some(DoorWhereInputId::Int(1)),
-                ..Default::default()
-            }),
             ..Default::default()
         })
         .await?;
@@ -198,102 +472,131 @@ let url = "wss://ws.test.azero.dev";
             .prisma
             .transaction()
             .create_door::<Door>(DoorCreateInput {
-                state: DoorState::Open.to_string(),
+                name: "Front door".to_string(),
+                location: None,
+                state: DoorState::Locked.to_string(),
+                version: 0,
+                relock_after: None,
+                relock_at: None,
+                battery: None,
             })?
             .execute::<DoorResponse>()
             .await?;
     }
 
-    // create users
-    let users_exist = state
-        .prisma
-        .first_user::<User>(FindFirstUserArgs {
-            filter: Some(UserWhereInput {
-                email: Some(UserWhereInputEmail::String(
-                    "seunlanlege@gmail.com".to_string(),
-                )),
-                ..Default::default()
-            }),
-            ..Default::default()
-        })
-        .await?;
-    if users_exist.is_none() {
-        state
-            .prisma
-            .transaction()
-            .create_user::<User>(UserCreateInput {
-                email: "seunlanlege@gmail.com".to_string(),
-                name: "seun".to_string(),
-                password: state.hasher.hash("Password@123")?,
-                role: "admin".to_string(),
-                device_token: None,
-            })?
-            .create_user::<User>(UserCreateInput {
-                email: "example@gmail.com".to_string(),
-                name: "nathaniel".to_string(),
-                password: state.hasher.hash("Password@123")?,
-                role: "admin".to_string(),
-                device_token: None,
-            })?
-            .create_user::<User>(UserCreateInput {
-                email: "oluwashinabajo@gmail.com".to_string(),
-                name: "ayomide".to_string(),
-                password: state.hasher.hash("Password@123")?,
-                role: "admin".to_string(),
-                device_token: None,
-            })?
-            .create_user::<User>(UserCreateInput {
-                email: "jummyfola013@gmail.com".to_string(),
-                name: "mum".to_string(),
-                password: state.hasher.hash("Password@123")?,
-                role: "admin".to_string(),
-                device_token: None,
-            })?
-            .create_user::<User>(UserCreateInput {
-                email: "debbiebajo@gmail.com".to_string(),
-                name: "damilola".to_string(),
-                password: state.hasher.hash("Password@123")?,
-                role: "admin".to_string(),
-                device_token: None,
-            })?
-            .execute::<TransactionResponse>()
-            .await?;
-    }
+    // without an admin, the first one is created through /setup
+    setup::prepare(&state).await?;
+
+    relock::resume(state.clone()).await?;
 
     let cors = CorsMiddleware::new()
         .allow_methods(
-            "Get, POST, OPTIONS"
+            "GET, POST, PUT, PATCH, DELETE, OPTIONS"
                 .parse::<tide::http::headers::HeaderValue>()
                 .unwrap(),
         )
         .allow_origin(Origin::from("*"))
         .allow_credentials(false);
 
+    let listen = state.config.listen.clone();
     let mut app = tide::with_state(state);
 
     app.with(cors);
 
-    app.with(After(|mut res: Response| async {
-        if let Some(err) = res.downcast_error::<tokio::io::Error>() {
-            let msg = format!("Error: {:?}", err);
-            res.set_status(StatusCode::NotFound);
-            res.set_body(msg);
-        }
-        Ok(res)
-    }));
+    // every failure is answered with an `error::ErrorResponse` body
+    app.with(After(|res: Response| async { Ok(error::render(res)) }));
 
+    app.at("/setup").post(setup_handler);
     app.at("/login").post(login_handler);
+    app.at("/refresh").post(refresh_handler);
+    app.at("/logout").with(Auth::user()).post(logout_handler);
+    app.at("/sessions").with(Auth::user()).get(list_sessions);
+    app.at("/sessions/:session_id")
+        .with(Auth::user())
+        .delete(revoke_session);
     // app.at("/nft").post(nft_handler);
     app.at("/reset").post(reset_handler);
-    app.at("/door").post(toggle_door_state);
+    app.at("/door").with(Auth::user()).post(toggle_door_state);
+    app.at("/doors")
+        .with(Auth::user())
+        .get(list_doors)
+        .with(Auth::admin())
+        .post(create_door);
+    app.at("/doors/:id").with(Auth::user()).post(door_action);
+    app.at("/doors/:id/state")
+        .with(Auth::user())
+        .get(door_state);
+    app.at("/doors/:id/relock")
+        .with(Auth::user())
+        .put(set_relock);
+    app.at("/doors/:id/battery")
+        .with(Auth::user())
+        .put(report_battery);
+    app.at("/doors/:id/toggle")
+        .with(Auth::user())
+        .post(toggle_door);
+    app.at("/doors/:id/grants")
+        .with(Auth::user())
+        .get(list_grants)
+        .post(put_grant);
+    app.at("/doors/:id/grants/:user_id")
+        .with(Auth::user())
+        .delete(revoke_grant);
+    app.at("/doors/:id/schedules")
+        .with(Auth::user())
+        .get(list_schedules)
+        .post(create_schedule);
+    app.at("/doors/:id/schedules/:schedule_id")
+        .with(Auth::user())
+        .delete(delete_schedule);
     app.at("/forgot").post(forgot_handler);
     app.at("/email").post(email_handler);
-    app.at("/polling").get(polling);
-    app.at("/notification").post(applenotification_handler);
+    app.at("/polling").with(Auth::user()).get(polling);
+    app.at("/events").with(Auth::admin()).get(list_events);
+    app.at("/users")
+        .with(Auth::admin())
+        .get(list_users)
+        .post(create_user);
+    app.at("/users/:user_id")
+        .with(Auth::admin())
+        .patch(update_user)
+        .delete(delete_user);
+    app.at("/users/:user_id/unlock")
+        .with(Auth::admin())
+        .post(unlock_user);
+    app.at("/events/stream")
+        .with(Auth::user().or_query_token())
+        .get(sse_handler);
+    app.at("/doorbell/press")
+        .with(Auth::admin())
+        .post(simulate_press);
+    app.at("/doorbell/rings").with(Auth::user()).get(list_rings);
+    app.at("/ws")
+        .with(Auth::user().or_query_token())
+        .get(ws_handler);
+    app.at("/notification")
+        .with(Auth::user())
+        .post(applenotification_handler);
+    app.at("/devices")
+        .with(Auth::user())
+        .get(list_devices)
+        .post(register_device);
+    app.at("/devices/:device_id")
+        .with(Auth::user())
+        .delete(unregister_device);
+    app.at("/notifications/preferences")
+        .with(Auth::user())
+        .get(get_preferences)
+        .put(put_preferences);
+    app.at("/templates").with(Auth::admin()).get(list_templates);
+    app.at("/templates/:locale/:key")
+        .with(Auth::admin())
+        .put(put_template)
+        .delete(delete_template);
 
     println!(r#"Server is running..."#);
 
-    app.listen("0.0.0.0:8080").await?;
+    app.listen(listen).await?;
 
     Ok(())
 }
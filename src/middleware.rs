@@ -0,0 +1,210 @@
+use crate::{
+    auth::{bearer_token, decode_jwt, Claims, Role},
+    error::Error,
+    session, TideState,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tide::{utils::async_trait, Middleware, Next, Request};
+
+/// Authenticates a request before its endpoint runs and stores the caller's
+/// `Claims` in the request extensions. Answers 401 without valid credentials
+/// and 403 when the caller's role is below the one the route requires. A
+/// second `Auth` on the same route only checks the role.
+#[derive(Debug, Clone)]
+pub struct Auth {
+    role: Role,
+    query_token: bool,
+}
+
+impl Auth {
+    pub fn user() -> Self {
+        Auth {
+            role: Role::User,
+            query_token: false,
+        }
+    }
+
+    pub fn admin() -> Self {
+        Auth {
+            role: Role::Admin,
+            query_token: false,
+        }
+    }
+
+    /// Also accepts the token as a `token` query parameter, for browser clients
+    /// that cannot set headers on a socket or an `EventSource`.
+    pub fn or_query_token(mut self) -> Self {
+        self.query_token = true;
+        self
+    }
+
+    fn token<S>(&self, req: &Request<S>) -> tide::Result<String> {
+        #[derive(Deserialize)]
+        struct TokenQuery {
+            token: Option<String>,
+        }
+
+        match req.header("Authorization") {
+            Some(header) => Ok(bearer_token(header.as_str())
+                .map_err(Error::into_tide)?
+                .to_string()),
+            None if self.query_token => req
+                .query::<TokenQuery>()?
+                .token
+                .ok_or_else(|| Error::NoAuthHeaderError.into_tide()),
+            None => Err(Error::NoAuthHeaderError.into_tide()),
+        }
+    }
+
+    async fn authenticate<S: Authenticator>(&self, req: &Request<S>) -> tide::Result<Claims> {
+        let token = self.token(req)?;
+        let claims = decode_jwt(&token, req.state().jwt_secret()).map_err(Error::into_tide)?;
+        req.state().check_session(&claims).await?;
+        Ok(claims)
+    }
+}
+
+/// What `Auth` needs from the server state.
+#[async_trait]
+pub trait Authenticator: Clone + Send + Sync + 'static {
+    fn jwt_secret(&self) -> &[u8];
+
+    /// Fails with 401 once the session behind `claims` has ended.
+    async fn check_session(&self, claims: &Claims) -> tide::Result<()>;
+}
+
+#[async_trait]
+impl Authenticator for Arc<TideState> {
+    fn jwt_secret(&self) -> &[u8] {
+        self.config.jwt_secret.as_bytes()
+    }
+
+    async fn check_session(&self, claims: &Claims) -> tide::Result<()> {
+        session::check(self, claims).await
+    }
+}
+
+#[async_trait]
+impl<S: Authenticator> Middleware<S> for Auth {
+    async fn handle(&self, mut req: Request<S>, next: Next<'_, S>) -> tide::Result {
+        let claims = match req.ext::<Claims>() {
+            Some(claims) => claims.clone(),
+            None => {
+                let claims = self.authenticate(&req).await?;
+                req.set_ext(claims.clone());
+                claims
+            }
+        };
+
+        if claims.role < self.role {
+            return Err(Error::NoPermissionError.into_tide());
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+/// Access to the claims `Auth` stored on a request.
+pub trait ClaimsExt {
+    fn claims(&self) -> tide::Result<Claims>;
+}
+
+impl<State> ClaimsExt for Request<State> {
+    /// Fails with 401 on routes that are not behind `Auth`.
+    fn claims(&self) -> tide::Result<Claims> {
+        self.ext::<Claims>()
+            .cloned()
+            .ok_or_else(|| Error::NoAuthHeaderError.into_tide())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Auth, Authenticator, ClaimsExt};
+    use crate::auth::{create_jwt, Claims, Role};
+    use std::sync::Arc;
+    use tide::{
+        http::{Method, Request, Response, Url},
+        utils::async_trait,
+        StatusCode,
+    };
+
+    const SECRET: &[u8] = b"test secret";
+
+    /// Ids of the sessions that have not ended.
+    #[derive(Clone)]
+    struct Sessions(Arc<Vec<i32>>);
+
+    #[async_trait]
+    impl Authenticator for Sessions {
+        fn jwt_secret(&self) -> &[u8] {
+            SECRET
+        }
+
+        async fn check_session(&self, claims: &Claims) -> tide::Result<()> {
+            if !self.0.contains(&claims.sid) {
+                return Err(tide::Error::from_str(
+                    StatusCode::Unauthorized,
+                    "Session has ended",
+                ));
+            }
+            Ok(())
+        }
+    }
+
+    fn token(role: Role, sid: i32) -> String {
+        let ttl = chrono::Duration::minutes(5);
+        create_jwt(1, &role, "ann@example.com".to_string(), sid, ttl, SECRET).unwrap()
+    }
+
+    async fn get(app: &tide::Server<Sessions>, path: &str, token: Option<String>) -> Response {
+        let mut req = Request::new(
+            Method::Get,
+            Url::parse("http://door.local").unwrap().join(path).unwrap(),
+        );
+        if let Some(token) = token {
+            req.insert_header("Authorization", format!("Bearer {}", token));
+        }
+        app.respond(req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn protected_routes_check_token_session_and_role() {
+        let mut app = tide::with_state(Sessions(Arc::new(vec![1, 2])));
+        app.at("/doors")
+            .with(Auth::user())
+            .get(|req: tide::Request<Sessions>| async move { Ok(req.claims()?.email) });
+        app.at("/users")
+            .with(Auth::admin())
+            .get(|_: tide::Request<Sessions>| async { Ok("users") });
+
+        assert_eq!(
+            get(&app, "/doors", None).await.status(),
+            StatusCode::Unauthorized
+        );
+        assert_eq!(
+            get(&app, "/users", None).await.status(),
+            StatusCode::Unauthorized
+        );
+        let forged = Some("not.a.token".to_string());
+        assert_eq!(
+            get(&app, "/doors", forged).await.status(),
+            StatusCode::Unauthorized
+        );
+
+        let mut res = get(&app, "/doors", Some(token(Role::User, 1))).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.body_string().await.unwrap(), "ann@example.com");
+        let res = get(&app, "/users", Some(token(Role::User, 1))).await;
+        assert_eq!(res.status(), StatusCode::Forbidden);
+
+        let res = get(&app, "/users", Some(token(Role::Admin, 2))).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        let res = get(&app, "/doors", Some(token(Role::Admin, 2))).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        // a revoked session no longer works, even with a valid token
+        let res = get(&app, "/doors", Some(token(Role::Admin, 3))).await;
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+    }
+}
@@ -0,0 +1,687 @@
+use crate::{
+    config::{ApnsConfig, Config, FcmConfig, GotifyConfig, NotifySettings, NtfyConfig},
+    error::Error,
+};
+use a2::{
+    Endpoint, ErrorReason, NotificationBuilder, NotificationOptions, PlainNotificationBuilder,
+};
+use chrono::{DateTime, Duration, Utc};
+use futures::{stream, StreamExt};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use reqwest::{header::CONTENT_TYPE, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+use tide::utils::async_trait;
+
+const FCM_SCOPE: &str = "https://www.googleapis.com/auth/firebase.messaging";
+
+/// Kind of device a push token belongs to, which decides the backend used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Platform {
+    /// APNs device token.
+    Ios,
+    /// FCM registration token.
+    Android,
+    /// Topic on the configured ntfy server.
+    Ntfy,
+}
+
+impl Platform {
+    pub fn from_str(platform: &str) -> Result<Platform, ()> {
+        let platform = match platform.to_lowercase().as_str() {
+            "ios" | "apns" => Platform::Ios,
+            "android" | "fcm" => Platform::Android,
+            "ntfy" => Platform::Ntfy,
+            _ => Err(())?,
+        };
+        Ok(platform)
+    }
+
+    /// Rejects tokens this platform could never deliver to.
+    pub fn check_token(&self, token: &str) -> Result<(), Error> {
+        let valid = match self {
+            Platform::Ios => !token.is_empty() && token.chars().all(|c| c.is_ascii_hexdigit()),
+            Platform::Android => !token.is_empty() && token.len() <= 4096,
+            // ntfy topics end up in the url path
+            Platform::Ntfy => {
+                (1..=64).contains(&token.len())
+                    && token
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            }
+        };
+        if !valid {
+            return Err(Error::InvalidInput(format!(
+                "invalid {} device token",
+                self
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let platform = match self {
+            Platform::Ios => "ios",
+            Platform::Android => "android",
+            Platform::Ntfy => "ntfy",
+        };
+        write!(f, "{}", platform)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Notification {
+    pub title: String,
+    pub body: String,
+}
+
+impl Notification {
+    pub fn new<T: Into<String>, B: Into<String>>(title: T, body: B) -> Self {
+        Notification {
+            title: title.into(),
+            body: body.into(),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum NotifyError {
+    /// The app was removed or the token expired; sending again is pointless.
+    #[error("device token is no longer registered")]
+    Unregistered,
+    /// Worth retrying: the service was unreachable, overloaded or failing.
+    #[error("{0}")]
+    Transient(String),
+    #[error("{0}")]
+    Failed(String),
+}
+
+fn failed(e: impl fmt::Display) -> NotifyError {
+    NotifyError::Failed(e.to_string())
+}
+
+fn transient(e: impl fmt::Display) -> NotifyError {
+    NotifyError::Transient(e.to_string())
+}
+
+/// Error for an unsuccessful answer from `service`; rate limits and server
+/// errors are transient.
+fn http_error(service: &str, status: StatusCode) -> NotifyError {
+    let message = format!("{} answered {}", service, status);
+    if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+        NotifyError::Transient(message)
+    } else {
+        NotifyError::Failed(message)
+    }
+}
+
+/// A way of delivering notifications.
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    /// Delivers `notification` to `destination`, which is a device token for
+    /// device backends and the configured channel for the others.
+    async fn send(&self, destination: &str, notification: &Notification)
+        -> Result<(), NotifyError>;
+}
+
+/// Apple Push Notification service, through one client signing its own tokens.
+pub struct ApnsNotifier {
+    client: a2::Client,
+    topic: Option<String>,
+}
+
+impl ApnsNotifier {
+    pub fn new(config: &ApnsConfig) -> Result<Self, Error> {
+        let mut private_key = std::fs::File::open(&config.key_file)?;
+        let endpoint = if config.sandbox {
+            Endpoint::Sandbox
+        } else {
+            Endpoint::Production
+        };
+        let client = a2::Client::token(&mut private_key, &config.key_id, &config.team_id, endpoint)
+            .map_err(|e| Error::ConfigError(format!("apns: {}", e)))?;
+
+        Ok(ApnsNotifier {
+            client,
+            topic: config.topic.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for ApnsNotifier {
+    async fn send(&self, token: &str, notification: &Notification) -> Result<(), NotifyError> {
+        let options = NotificationOptions {
+            apns_topic: self.topic.as_deref(),
+            ..Default::default()
+        };
+        let mut builder = PlainNotificationBuilder::new(notification.body.as_ref());
+        builder.set_sound("default");
+        builder.set_badge(1u32);
+        let payload = builder.build(token, options);
+
+        match self.client.send(payload).await {
+            Ok(_) => Ok(()),
+            Err(a2::Error::ResponseError(response)) => match response.error.map(|e| e.reason) {
+                Some(ErrorReason::BadDeviceToken) | Some(ErrorReason::Unregistered) => {
+                    Err(NotifyError::Unregistered)
+                }
+                reason => {
+                    let message = format!("apns answered {}: {:?}", response.code, reason);
+                    match response.code {
+                        429 | 500..=599 => Err(NotifyError::Transient(message)),
+                        _ => Err(NotifyError::Failed(message)),
+                    }
+                }
+            },
+            // connection problems, mostly
+            Err(e) => Err(transient(e)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ServiceAccount {
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+#[derive(Serialize)]
+struct ServiceAccountClaims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: i64,
+}
+
+struct AccessToken {
+    token: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// Firebase Cloud Messaging HTTP v1, authorized with a service account.
+pub struct FcmNotifier {
+    http: reqwest::Client,
+    project_id: String,
+    account: ServiceAccount,
+    key: EncodingKey,
+    token: futures::lock::Mutex<Option<AccessToken>>,
+}
+
+impl FcmNotifier {
+    pub fn new(config: &FcmConfig) -> Result<Self, Error> {
+        let invalid = |e: &dyn fmt::Display| Error::ConfigError(format!("fcm: {}", e));
+        let account = std::fs::read_to_string(&config.service_account_file)?;
+        let account = serde_json::from_str::<ServiceAccount>(&account).map_err(|e| invalid(&e))?;
+        let key =
+            EncodingKey::from_rsa_pem(account.private_key.as_bytes()).map_err(|e| invalid(&e))?;
+
+        Ok(FcmNotifier {
+            http: reqwest::Client::new(),
+            project_id: config.project_id.clone(),
+            account,
+            key,
+            token: futures::lock::Mutex::new(None),
+        })
+    }
+
+    /// OAuth token for the FCM API, renewed shortly before it expires.
+    async fn access_token(&self) -> Result<String, NotifyError> {
+        let mut cached = self.token.lock().await;
+        if let Some(token) = cached.as_ref() {
+            if token.expires_at > Utc::now() + Duration::minutes(1) {
+                return Ok(token.token.clone());
+            }
+        }
+
+        let now = Utc::now().timestamp();
+        let claims = ServiceAccountClaims {
+            iss: &self.account.client_email,
+            scope: FCM_SCOPE,
+            aud: &self.account.token_uri,
+            iat: now,
+            exp: now + 3600,
+        };
+        let assertion = jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &self.key)
+            .map_err(failed)?;
+        let response = self
+            .http
+            .post(&self.account.token_uri)
+            .form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", &assertion),
+            ])
+            .send()
+            .await
+            .map_err(transient)?;
+        if !response.status().is_success() {
+            return Err(http_error("fcm token request", response.status()));
+        }
+        let granted = response.text().await.map_err(failed)?;
+        let granted = serde_json::from_str::<TokenResponse>(&granted).map_err(failed)?;
+
+        *cached = Some(AccessToken {
+            token: granted.access_token.clone(),
+            expires_at: Utc::now() + Duration::seconds(granted.expires_in),
+        });
+        Ok(granted.access_token)
+    }
+}
+
+#[async_trait]
+impl Notifier for FcmNotifier {
+    async fn send(&self, token: &str, notification: &Notification) -> Result<(), NotifyError> {
+        let access_token = self.access_token().await?;
+        let message = serde_json::json!({
+            "message": {
+                "token": token,
+                "notification": notification,
+            }
+        });
+        let response = self
+            .http
+            .post(format!(
+                "https://fcm.googleapis.com/v1/projects/{}/messages:send",
+                self.project_id
+            ))
+            .bearer_auth(access_token)
+            .header(CONTENT_TYPE, "application/json")
+            .body(message.to_string())
+            .send()
+            .await
+            .map_err(transient)?;
+
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            // FCM's UNREGISTERED
+            StatusCode::NOT_FOUND => Err(NotifyError::Unregistered),
+            status => Err(http_error("fcm", status)),
+        }
+    }
+}
+
+/// Posts to topics on an ntfy server, see <https://ntfy.sh>.
+pub struct NtfyNotifier {
+    http: reqwest::Client,
+    server: String,
+    token: Option<String>,
+}
+
+impl NtfyNotifier {
+    pub fn new(config: &NtfyConfig) -> Self {
+        NtfyNotifier {
+            http: reqwest::Client::new(),
+            server: config.server.trim_end_matches('/').to_string(),
+            token: config.token.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for NtfyNotifier {
+    async fn send(&self, topic: &str, notification: &Notification) -> Result<(), NotifyError> {
+        Platform::Ntfy.check_token(topic).map_err(failed)?;
+
+        let mut request = self
+            .http
+            .post(format!("{}/{}", self.server, topic))
+            .header("Title", &notification.title)
+            .body(notification.body.clone());
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await.map_err(transient)?;
+        if !response.status().is_success() {
+            return Err(http_error("ntfy", response.status()));
+        }
+        Ok(())
+    }
+}
+
+/// Posts to a Gotify server; the destination is the application token.
+pub struct GotifyNotifier {
+    http: reqwest::Client,
+    server: String,
+    priority: u8,
+}
+
+impl GotifyNotifier {
+    pub fn new(config: &GotifyConfig) -> Self {
+        GotifyNotifier {
+            http: reqwest::Client::new(),
+            server: config.server.trim_end_matches('/').to_string(),
+            priority: config.priority,
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for GotifyNotifier {
+    async fn send(&self, app_token: &str, notification: &Notification) -> Result<(), NotifyError> {
+        let message = serde_json::json!({
+            "title": notification.title,
+            "message": notification.body,
+            "priority": self.priority,
+        });
+        let response = self
+            .http
+            .post(format!("{}/message", self.server))
+            .header("X-Gotify-Key", app_token)
+            .header(CONTENT_TYPE, "application/json")
+            .body(message.to_string())
+            .send()
+            .await
+            .map_err(transient)?;
+        if !response.status().is_success() {
+            return Err(http_error("gotify", response.status()));
+        }
+        Ok(())
+    }
+}
+
+/// Posts every notification as JSON to a URL; the destination is the URL.
+pub struct WebhookNotifier {
+    http: reqwest::Client,
+    secret: Option<String>,
+}
+
+impl WebhookNotifier {
+    pub fn new(secret: Option<String>) -> Self {
+        WebhookNotifier {
+            http: reqwest::Client::new(),
+            secret,
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for WebhookNotifier {
+    async fn send(&self, url: &str, notification: &Notification) -> Result<(), NotifyError> {
+        let body = serde_json::to_string(notification).map_err(failed)?;
+        let mut request = self
+            .http
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body);
+        if let Some(secret) = &self.secret {
+            request = request.bearer_auth(secret);
+        }
+        let response = request.send().await.map_err(transient)?;
+        if !response.status().is_success() {
+            return Err(http_error("webhook", response.status()));
+        }
+        Ok(())
+    }
+}
+
+/// Records notifications instead of sending them, for development and tests.
+/// Destinations listed in `unregistered` fail like a removed app would, those
+/// in `flaky` fail transiently the given number of times.
+#[derive(Clone, Default)]
+pub struct MockNotifier {
+    pub sent: Arc<Mutex<Vec<(String, Notification)>>>,
+    pub unregistered: Arc<Mutex<Vec<String>>>,
+    pub flaky: Arc<Mutex<HashMap<String, u32>>>,
+}
+
+#[async_trait]
+impl Notifier for MockNotifier {
+    async fn send(
+        &self,
+        destination: &str,
+        notification: &Notification,
+    ) -> Result<(), NotifyError> {
+        if self
+            .unregistered
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|token| token == destination)
+        {
+            return Err(NotifyError::Unregistered);
+        }
+        if let Some(failures) = self.flaky.lock().unwrap().get_mut(destination) {
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(NotifyError::Transient("try again later".to_string()));
+            }
+        }
+        self.sent
+            .lock()
+            .unwrap()
+            .push((destination.to_string(), notification.clone()));
+        Ok(())
+    }
+}
+
+/// Outcome of delivering to one device or channel.
+#[derive(Debug)]
+pub struct Delivery {
+    /// `None` for channels such as the webhook, which are not devices.
+    pub platform: Option<Platform>,
+    pub destination: String,
+    /// Sends made, retries included.
+    pub attempts: u32,
+    pub result: Result<(), NotifyError>,
+}
+
+/// Backend for each device platform, plus channels that receive every
+/// notification regardless of devices.
+#[derive(Default)]
+pub struct Notifiers {
+    settings: NotifySettings,
+    devices: HashMap<Platform, Box<dyn Notifier>>,
+    channels: Vec<(String, Box<dyn Notifier>)>,
+}
+
+impl Notifiers {
+    /// Sets up every backend with a section in `config`.
+    pub fn from_config(config: &Config) -> Result<Notifiers, Error> {
+        let mut notifiers = Notifiers::new(config.notify.clone());
+        if let Some(apns) = &config.apns {
+            notifiers.insert(Platform::Ios, Box::new(ApnsNotifier::new(apns)?));
+        }
+        if let Some(fcm) = &config.fcm {
+            notifiers.insert(Platform::Android, Box::new(FcmNotifier::new(fcm)?));
+        }
+        if let Some(ntfy) = &config.ntfy {
+            notifiers.insert(Platform::Ntfy, Box::new(NtfyNotifier::new(ntfy)));
+        }
+        if let Some(gotify) = &config.gotify {
+            notifiers.add_channel(
+                gotify.app_token.clone(),
+                Box::new(GotifyNotifier::new(gotify)),
+            );
+        }
+        if let Some(webhook) = &config.webhook {
+            notifiers.add_channel(
+                webhook.url.clone(),
+                Box::new(WebhookNotifier::new(webhook.secret.clone())),
+            );
+        }
+        Ok(notifiers)
+    }
+
+    pub fn new(settings: NotifySettings) -> Self {
+        Notifiers {
+            settings,
+            ..Default::default()
+        }
+    }
+
+    pub fn insert(&mut self, platform: Platform, notifier: Box<dyn Notifier>) {
+        self.devices.insert(platform, notifier);
+    }
+
+    pub fn add_channel(&mut self, destination: String, notifier: Box<dyn Notifier>) {
+        self.channels.push((destination, notifier));
+    }
+
+    /// Whether devices of `platform` can be notified.
+    pub fn supports(&self, platform: Platform) -> bool {
+        self.devices.contains_key(&platform)
+    }
+
+    /// Sends to `destination`, retrying transient failures with a doubling delay.
+    async fn deliver(
+        &self,
+        notifier: &dyn Notifier,
+        destination: &str,
+        notification: &Notification,
+    ) -> (u32, Result<(), NotifyError>) {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match notifier.send(destination, notification).await {
+                Err(NotifyError::Transient(_)) if attempts <= self.settings.retries => {
+                    let delay = self.settings.retry_delay_ms << (attempts - 1).min(16);
+                    tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+                }
+                result => return (attempts, result),
+            }
+        }
+    }
+
+    /// Sends each of `devices` its own notification and every channel
+    /// `broadcast`, at most `max_concurrent` at a time. Devices of platforms
+    /// without a backend are skipped; one result is returned per send, in order.
+    pub async fn send(
+        &self,
+        devices: &[(Platform, String, Notification)],
+        broadcast: &Notification,
+    ) -> Vec<Delivery> {
+        let device_sends = devices
+            .iter()
+            .filter_map(|(platform, token, notification)| {
+                let notifier = self.devices.get(platform)?;
+                Some((Some(*platform), token, notifier, notification))
+            });
+        let channel_sends = self
+            .channels
+            .iter()
+            .map(|(destination, notifier)| (None, destination, notifier, broadcast));
+
+        stream::iter(device_sends.chain(channel_sends))
+            .map(
+                |(platform, destination, notifier, notification)| async move {
+                    let (attempts, result) = self
+                        .deliver(notifier.as_ref(), destination, notification)
+                        .await;
+                    Delivery {
+                        platform,
+                        destination: destination.clone(),
+                        attempts,
+                        result,
+                    }
+                },
+            )
+            .buffered(self.settings.max_concurrent.max(1))
+            .collect()
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_platforms_and_checks_tokens() {
+        assert_eq!(Platform::from_str("iOS"), Ok(Platform::Ios));
+        assert_eq!(Platform::from_str("fcm"), Ok(Platform::Android));
+        assert!(Platform::from_str("pager").is_err());
+
+        assert!(Platform::Ios.check_token("a1b2c3").is_ok());
+        assert!(Platform::Ios.check_token("not hex").is_err());
+        assert!(Platform::Ntfy.check_token("front-door_1").is_ok());
+        assert!(Platform::Ntfy.check_token("../admin").is_err());
+    }
+
+    #[tokio::test]
+    async fn sends_to_devices_of_configured_platforms_and_every_channel() {
+        let phones = MockNotifier::default();
+        phones.unregistered.lock().unwrap().push("gone".to_string());
+        let webhook = MockNotifier::default();
+
+        let mut notifiers = Notifiers::default();
+        notifiers.insert(Platform::Ios, Box::new(phones.clone()));
+        notifiers.add_channel("http://hooks".to_string(), Box::new(webhook.clone()));
+        assert!(notifiers.supports(Platform::Ios));
+        assert!(!notifiers.supports(Platform::Android));
+
+        let opened = Notification::new("Door", "Mum opened the door");
+        let devices = vec![
+            (Platform::Ios, "abc".to_string(), opened.clone()),
+            (Platform::Ios, "gone".to_string(), opened.clone()),
+            (Platform::Android, "skipped".to_string(), opened),
+        ];
+        let deliveries = notifiers
+            .send(
+                &devices,
+                &Notification::new("Door", "Mum hat die Tür geöffnet"),
+            )
+            .await;
+
+        assert_eq!(deliveries.len(), 3);
+        assert!(deliveries[0].result.is_ok());
+        assert!(matches!(
+            deliveries[1].result,
+            Err(NotifyError::Unregistered)
+        ));
+        assert_eq!(deliveries[2].platform, None);
+
+        let sent = phones.sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, "abc");
+        assert_eq!(sent[0].1.body, "Mum opened the door");
+        let hooks = webhook.sent.lock().unwrap();
+        assert_eq!(hooks[0].0, "http://hooks");
+        assert_eq!(hooks[0].1.body, "Mum hat die Tür geöffnet");
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures_only() {
+        let phones = MockNotifier::default();
+        phones.flaky.lock().unwrap().insert("slow".to_string(), 2);
+        phones.flaky.lock().unwrap().insert("down".to_string(), 5);
+
+        let mut notifiers = Notifiers::new(NotifySettings {
+            max_concurrent: 2,
+            retries: 2,
+            retry_delay_ms: 1,
+        });
+        notifiers.insert(Platform::Ios, Box::new(phones.clone()));
+
+        let ring = Notification::new("Door", "Ring");
+        let devices = ["slow", "down", "fine"]
+            .iter()
+            .map(|token| (Platform::Ios, token.to_string(), ring.clone()))
+            .collect::<Vec<_>>();
+        let deliveries = notifiers.send(&devices, &ring).await;
+
+        assert_eq!(deliveries[0].attempts, 3);
+        assert!(deliveries[0].result.is_ok());
+        assert_eq!(deliveries[1].attempts, 3);
+        assert!(matches!(
+            deliveries[1].result,
+            Err(NotifyError::Transient(_))
+        ));
+        assert_eq!(deliveries[2].attempts, 1);
+        assert_eq!(phones.sent.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,546 @@
+use crate::{
+    access::grantees,
+    auth::Role,
+    error::{db_error, Error},
+    middleware::ClaimsExt,
+    prisma::{
+        DeleteManyNotificationPreferenceArgs, FindManyNotificationPreferenceArgs, FindManyUserArgs,
+        NotificationPreference, NotificationPreferenceCreateInput,
+        NotificationPreferenceUpdateInput, NotificationPreferenceUpdateInputDoorEvents,
+        NotificationPreferenceUpdateInputDoorIds, NotificationPreferenceUpdateInputDoorbell,
+        NotificationPreferenceUpdateInputFailedAttempts,
+        NotificationPreferenceUpdateInputLowBattery, NotificationPreferenceUpdateInputOwnActions,
+        NotificationPreferenceUpdateInputQuietEnd, NotificationPreferenceUpdateInputQuietStart,
+        NotificationPreferenceUpdateInputUtcOffset, NotificationPreferenceWhereInput,
+        NotificationPreferenceWhereInputUserId, NotificationPreferenceWhereUniqueInput,
+        UpdateOneNotificationPreferenceArgs, UpdateOneUserArgs, User, UserUpdateInput,
+        UserUpdateInputLocale, UserWhereInput, UserWhereInputDisabled, UserWhereUniqueInput,
+    },
+    schedule::{Window, Zone},
+    templates::check_locale,
+    PreferencesRequest, TideState,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+use tide::{Body, Error as TideError, Request, StatusCode};
+
+const EVERY_DAY: &str = "mon,tue,wed,thu,fri,sat,sun";
+
+/// Kinds of notification users subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Topic {
+    /// A door was locked, unlocked or opened.
+    DoorEvents,
+    Doorbell,
+    /// Someone was refused at a door or an account was locked.
+    FailedAttempts,
+    /// Lock hardware reporting a weak battery.
+    LowBattery,
+}
+
+/// What a notification is about, which decides who receives it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subject {
+    /// `None` reaches everyone regardless of their preferences.
+    pub topic: Option<Topic>,
+    pub door_id: Option<i32>,
+    /// User who caused it, who usually does not need to be told.
+    pub actor_id: Option<i32>,
+}
+
+impl Subject {
+    pub fn new(topic: Topic) -> Self {
+        Subject {
+            topic: Some(topic),
+            door_id: None,
+            actor_id: None,
+        }
+    }
+
+    /// For test pushes, which ignore preferences.
+    pub fn everyone() -> Self {
+        Subject {
+            topic: None,
+            door_id: None,
+            actor_id: None,
+        }
+    }
+
+    pub fn door(mut self, door_id: i32) -> Self {
+        self.door_id = Some(door_id);
+        self
+    }
+
+    pub fn actor(mut self, user_id: i32) -> Self {
+        self.actor_id = Some(user_id);
+        self
+    }
+
+    /// Whether someone may be told about this at all, whatever their
+    /// preferences: like on the event stream, news about a door needs access
+    /// to it and everyone hears the doorbell. Failed attempts are for admins.
+    pub fn visible_to(&self, admin: bool, views_door: bool) -> bool {
+        if admin {
+            return true;
+        }
+        match self.topic {
+            Some(Topic::FailedAttempts) => false,
+            Some(Topic::Doorbell) | None => true,
+            Some(Topic::DoorEvents) | Some(Topic::LowBattery) => {
+                self.door_id.is_none() || views_door
+            }
+        }
+    }
+}
+
+/// A user's notification settings; users who never set any get the defaults.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preferences {
+    pub door_events: bool,
+    pub doorbell: bool,
+    pub failed_attempts: bool,
+    pub low_battery: bool,
+    /// Also notify about the user's own actions.
+    pub own_actions: bool,
+    /// Doors to hear about, every door when `None`.
+    pub doors: Option<Vec<i32>>,
+    /// Daily window without any notifications.
+    pub quiet_hours: Option<Window>,
+    /// Minutes ahead of UTC, for quiet hours and times in messages.
+    pub utc_offset: i32,
+}
+
+impl Default for Preferences {
+    fn default() -> Self {
+        Preferences {
+            door_events: true,
+            doorbell: true,
+            failed_attempts: true,
+            low_battery: true,
+            own_actions: false,
+            doors: None,
+            quiet_hours: None,
+            utc_offset: 0,
+        }
+    }
+}
+
+impl Preferences {
+    pub fn parse(stored: &NotificationPreference) -> Result<Self, String> {
+        let doors = stored.door_ids.as_deref().map(parse_door_ids).transpose()?;
+        let quiet_hours = quiet_hours(
+            stored.quiet_start.as_deref(),
+            stored.quiet_end.as_deref(),
+            stored.utc_offset,
+        )?;
+
+        Ok(Preferences {
+            door_events: stored.door_events,
+            doorbell: stored.doorbell,
+            failed_attempts: stored.failed_attempts,
+            low_battery: stored.low_battery,
+            own_actions: stored.own_actions,
+            doors,
+            quiet_hours,
+            utc_offset: stored.utc_offset,
+        })
+    }
+
+    /// Whether `user_id`, holding these preferences, wants to hear about `subject` at `now`.
+    pub fn wants(&self, user_id: i32, subject: &Subject, now: DateTime<Utc>) -> bool {
+        let topic = match subject.topic {
+            Some(topic) => topic,
+            None => return true,
+        };
+        let subscribed = match topic {
+            Topic::DoorEvents => self.door_events,
+            Topic::Doorbell => self.doorbell,
+            Topic::FailedAttempts => self.failed_attempts,
+            Topic::LowBattery => self.low_battery,
+        };
+        let own = subject.actor_id == Some(user_id) && !self.own_actions;
+        let other_door = match (&self.doors, subject.door_id) {
+            (Some(doors), Some(door_id)) => !doors.contains(&door_id),
+            _ => false,
+        };
+        let quiet = matches!(&self.quiet_hours, Some(window) if window.contains(now));
+
+        subscribed && !own && !other_door && !quiet
+    }
+}
+
+fn parse_door_ids(door_ids: &str) -> Result<Vec<i32>, String> {
+    if door_ids.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    door_ids
+        .split(',')
+        .map(|id| {
+            id.trim()
+                .parse::<i32>()
+                .map_err(|_e| format!("invalid door id {}", id))
+        })
+        .collect()
+}
+
+fn quiet_hours(
+    start: Option<&str>,
+    end: Option<&str>,
+    utc_offset: i32,
+) -> Result<Option<Window>, String> {
+    match (start, end) {
+        (None, None) => Ok(None),
+        (Some(start), Some(end)) => Zone::from_offset(utc_offset)
+            .and_then(|zone| Window::from_parts(Some(EVERY_DAY), None, start, end, zone))
+            .map(Some),
+        _ => Err("quiet_start and quiet_end must be set together".to_string()),
+    }
+}
+
+/// Someone to notify, with what rendering their messages takes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recipient {
+    pub user_id: i32,
+    pub locale: String,
+    pub utc_offset: i32,
+}
+
+/// Enabled users who may know about `subject` and want to hear about it now.
+pub async fn recipients(state: &TideState, subject: &Subject) -> tide::Result<Vec<Recipient>> {
+    let users = state
+        .prisma
+        .users::<User>(FindManyUserArgs {
+            filter: Some(UserWhereInput {
+                disabled: Some(UserWhereInputDisabled::Boolean(false)),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?;
+    let stored = state
+        .prisma
+        .notification_preferences::<NotificationPreference>(FindManyNotificationPreferenceArgs {
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?
+        .into_iter()
+        .map(|stored| (stored.user_id, stored))
+        .collect::<HashMap<_, _>>();
+    let viewers = match subject.door_id {
+        Some(door_id) => grantees(state, door_id).await?,
+        None => HashSet::new(),
+    };
+
+    let now = Utc::now();
+    let recipients = users
+        .into_iter()
+        .filter_map(|user| {
+            let admin = Role::from_str(&user.role) == Role::Admin;
+            if !subject.visible_to(admin, viewers.contains(&user.id)) {
+                return None;
+            }
+            let preferences = match stored.get(&user.id).map(Preferences::parse) {
+                Some(Ok(preferences)) => preferences,
+                Some(Err(e)) => {
+                    println!("Ignoring notification preferences of {}: {}", user.email, e);
+                    Preferences::default()
+                }
+                None => Preferences::default(),
+            };
+            if !preferences.wants(user.id, subject, now) {
+                return None;
+            }
+            Some(Recipient {
+                user_id: user.id,
+                locale: user.locale,
+                utc_offset: preferences.utc_offset,
+            })
+        })
+        .collect();
+    Ok(recipients)
+}
+
+/// Removes the preferences of `user_id`, e.g. when the user is deleted.
+pub async fn remove(state: &TideState, user_id: i32) -> tide::Result<()> {
+    state
+        .prisma
+        .delete_many_notification_preferences(DeleteManyNotificationPreferenceArgs {
+            filter: Some(NotificationPreferenceWhereInput {
+                user_id: Some(NotificationPreferenceWhereInputUserId::Int(user_id)),
+                ..Default::default()
+            }),
+        })
+        .await
+        .map_err(db_error)?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct PreferencesResponse {
+    pub door_events: bool,
+    pub doorbell: bool,
+    pub failed_attempts: bool,
+    pub low_battery: bool,
+    pub own_actions: bool,
+    pub door_ids: Option<Vec<i32>>,
+    pub quiet_start: Option<String>,
+    pub quiet_end: Option<String>,
+    pub utc_offset: i32,
+    pub locale: String,
+}
+
+impl PreferencesResponse {
+    fn new(preferences: Preferences, locale: String) -> Self {
+        let quiet_hours = preferences.quiet_hours.as_ref();
+        PreferencesResponse {
+            door_events: preferences.door_events,
+            doorbell: preferences.doorbell,
+            failed_attempts: preferences.failed_attempts,
+            low_battery: preferences.low_battery,
+            own_actions: preferences.own_actions,
+            door_ids: preferences.doors,
+            quiet_start: quiet_hours.map(|window| window.start.format("%H:%M").to_string()),
+            quiet_end: quiet_hours.map(|window| window.end.format("%H:%M").to_string()),
+            utc_offset: preferences.utc_offset,
+            locale,
+        }
+    }
+}
+
+async fn stored_preferences(
+    state: &TideState,
+    user_id: i32,
+) -> tide::Result<Option<NotificationPreference>> {
+    state
+        .prisma
+        .notification_preference::<NotificationPreference>(NotificationPreferenceWhereUniqueInput {
+            user_id: Some(user_id),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)
+}
+
+async fn find_user(state: &TideState, user_id: i32) -> tide::Result<User> {
+    state
+        .prisma
+        .user::<User>(UserWhereUniqueInput {
+            id: Some(user_id),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| TideError::from_str(StatusCode::NotFound, "User not found"))
+}
+
+fn json(body: &PreferencesResponse) -> tide::Result {
+    let mut res = tide::Response::new(StatusCode::Ok);
+    res.set_body(Body::from_json(body)?);
+    Ok(res)
+}
+
+pub async fn get_preferences(req: Request<Arc<TideState>>) -> tide::Result {
+    let claims = req.claims()?;
+    let user = find_user(req.state(), claims.sub as i32).await?;
+    let preferences = match stored_preferences(req.state(), user.id).await? {
+        Some(stored) => Preferences::parse(&stored).unwrap_or_default(),
+        None => Preferences::default(),
+    };
+    json(&PreferencesResponse::new(preferences, user.locale))
+}
+
+/// Replaces the caller's preferences. Left out settings take their defaults,
+/// except the locale which stays as it is.
+pub async fn put_preferences(mut req: Request<Arc<TideState>>) -> tide::Result {
+    let request = req.body_json::<PreferencesRequest>().await?;
+    let claims = req.claims()?;
+    let state = req.state();
+    let user_id = claims.sub as i32;
+
+    let defaults = Preferences::default();
+    let door_ids = request.door_ids.map(|ids| {
+        ids.iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    });
+    let utc_offset = request.utc_offset.unwrap_or(0);
+    quiet_hours(
+        request.quiet_start.as_deref(),
+        request.quiet_end.as_deref(),
+        utc_offset,
+    )
+    .map_err(|e| Error::InvalidInput(e).into_tide())?;
+    let locale = request
+        .locale
+        .as_deref()
+        .map(check_locale)
+        .transpose()
+        .map_err(Error::into_tide)?;
+
+    let user = find_user(state, user_id).await?;
+    let user = match locale {
+        Some(locale) if locale != user.locale => state
+            .prisma
+            .update_user::<User>(UpdateOneUserArgs {
+                data: UserUpdateInput {
+                    locale: Some(UserUpdateInputLocale::String(locale)),
+                    ..Default::default()
+                },
+                filter: UserWhereUniqueInput {
+                    id: Some(user.id),
+                    ..Default::default()
+                },
+            })
+            .await
+            .map_err(db_error)?,
+        _ => user,
+    };
+
+    let stored = match stored_preferences(state, user_id).await? {
+        Some(stored) => state
+            .prisma
+            .update_notification_preference::<NotificationPreference>(
+                UpdateOneNotificationPreferenceArgs {
+                    data: NotificationPreferenceUpdateInput {
+                        door_events: Some(NotificationPreferenceUpdateInputDoorEvents::Boolean(
+                            request.door_events.unwrap_or(defaults.door_events),
+                        )),
+                        doorbell: Some(NotificationPreferenceUpdateInputDoorbell::Boolean(
+                            request.doorbell.unwrap_or(defaults.doorbell),
+                        )),
+                        failed_attempts: Some(
+                            NotificationPreferenceUpdateInputFailedAttempts::Boolean(
+                                request.failed_attempts.unwrap_or(defaults.failed_attempts),
+                            ),
+                        ),
+                        low_battery: Some(NotificationPreferenceUpdateInputLowBattery::Boolean(
+                            request.low_battery.unwrap_or(defaults.low_battery),
+                        )),
+                        own_actions: Some(NotificationPreferenceUpdateInputOwnActions::Boolean(
+                            request.own_actions.unwrap_or(defaults.own_actions),
+                        )),
+                        door_ids: Some(
+                            door_ids.map(NotificationPreferenceUpdateInputDoorIds::String),
+                        ),
+                        quiet_start: Some(
+                            request
+                                .quiet_start
+                                .map(NotificationPreferenceUpdateInputQuietStart::String),
+                        ),
+                        quiet_end: Some(
+                            request
+                                .quiet_end
+                                .map(NotificationPreferenceUpdateInputQuietEnd::String),
+                        ),
+                        utc_offset: Some(NotificationPreferenceUpdateInputUtcOffset::Int(
+                            utc_offset,
+                        )),
+                        ..Default::default()
+                    },
+                    filter: NotificationPreferenceWhereUniqueInput {
+                        id: Some(stored.id),
+                        ..Default::default()
+                    },
+                },
+            )
+            .await
+            .map_err(db_error)?,
+        None => state
+            .prisma
+            .create_notification_preference::<NotificationPreference>(
+                NotificationPreferenceCreateInput {
+                    user_id,
+                    door_events: request.door_events.unwrap_or(defaults.door_events),
+                    doorbell: request.doorbell.unwrap_or(defaults.doorbell),
+                    failed_attempts: request.failed_attempts.unwrap_or(defaults.failed_attempts),
+                    low_battery: request.low_battery.unwrap_or(defaults.low_battery),
+                    own_actions: request.own_actions.unwrap_or(defaults.own_actions),
+                    door_ids,
+                    quiet_start: request.quiet_start,
+                    quiet_end: request.quiet_end,
+                    utc_offset,
+                },
+            )
+            .await
+            .map_err(db_error)?,
+    };
+
+    let preferences =
+        Preferences::parse(&stored).map_err(|e| Error::InvalidInput(e).into_tide())?;
+    json(&PreferencesResponse::new(preferences, user.locale))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{quiet_hours, Preferences, Subject, Topic};
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn subscriptions_doors_and_actor_decide_who_hears() {
+        let now = Utc.ymd(2022, 10, 18).and_hms(12, 0, 0);
+        let opened = Subject::new(Topic::DoorEvents).door(2).actor(7);
+        let defaults = Preferences::default();
+        assert!(defaults.wants(1, &opened, now));
+        assert!(!defaults.wants(7, &opened, now));
+        assert!(defaults.wants(7, &Subject::everyone(), now));
+
+        let front_door_only = Preferences {
+            doors: Some(vec![1]),
+            own_actions: true,
+            ..Preferences::default()
+        };
+        assert!(!front_door_only.wants(7, &opened, now));
+        assert!(front_door_only.wants(7, &Subject::new(Topic::DoorEvents).door(1).actor(7), now));
+
+        let no_bell = Preferences {
+            doorbell: false,
+            ..Preferences::default()
+        };
+        assert!(!no_bell.wants(1, &Subject::new(Topic::Doorbell), now));
+        assert!(no_bell.wants(1, &opened, now));
+    }
+
+    #[test]
+    fn door_news_needs_access_and_failed_attempts_are_for_admins() {
+        let opened = Subject::new(Topic::DoorEvents).door(2);
+        assert!(opened.visible_to(false, true));
+        assert!(!opened.visible_to(false, false));
+        assert!(opened.visible_to(true, false));
+
+        let refused = Subject::new(Topic::FailedAttempts).door(2);
+        assert!(!refused.visible_to(false, true));
+        assert!(refused.visible_to(true, false));
+
+        assert!(Subject::new(Topic::Doorbell)
+            .door(2)
+            .visible_to(false, false));
+        assert!(Subject::everyone().visible_to(false, false));
+    }
+
+    #[test]
+    fn quiet_hours_run_past_midnight_in_local_time() {
+        let night = Preferences {
+            quiet_hours: quiet_hours(Some("22:00"), Some("07:00"), 60).unwrap(),
+            ..Preferences::default()
+        };
+        let bell = Subject::new(Topic::Doorbell);
+        assert!(!night.wants(1, &bell, Utc.ymd(2022, 10, 18).and_hms(22, 30, 0)));
+        assert!(!night.wants(1, &bell, Utc.ymd(2022, 10, 18).and_hms(5, 30, 0)));
+        assert!(night.wants(1, &bell, Utc.ymd(2022, 10, 18).and_hms(6, 30, 0)));
+        assert!(night.wants(
+            1,
+            &Subject::everyone(),
+            Utc.ymd(2022, 10, 18).and_hms(23, 0, 0)
+        ));
+
+        assert!(quiet_hours(Some("22:00"), None, 0).is_err());
+        assert!(quiet_hours(Some("25:00"), Some("07:00"), 0).is_err());
+    }
+}
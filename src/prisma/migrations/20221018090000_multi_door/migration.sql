@@ -0,0 +1,6 @@
+-- AlterTable
+ALTER TABLE "Door" ADD COLUMN "name" TEXT NOT NULL DEFAULT 'Front door';
+ALTER TABLE "Door" ADD COLUMN "location" TEXT;
+
+-- CreateIndex
+CREATE UNIQUE INDEX "Door_name_key" ON "Door"("name");
@@ -0,0 +1,10 @@
+-- CreateTable
+CREATE TABLE "AccessGrant" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "user_id" INTEGER NOT NULL,
+    "door_id" INTEGER NOT NULL,
+    "permission" TEXT NOT NULL
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "AccessGrant_user_id_door_id_key" ON "AccessGrant"("user_id", "door_id");
@@ -0,0 +1,11 @@
+-- CreateTable
+CREATE TABLE "AccessSchedule" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "user_id" INTEGER NOT NULL,
+    "door_id" INTEGER NOT NULL,
+    "weekdays" TEXT,
+    "date" TEXT,
+    "start_time" TEXT NOT NULL,
+    "end_time" TEXT NOT NULL,
+    "utc_offset" INTEGER NOT NULL DEFAULT 0
+);
@@ -0,0 +1,18 @@
+-- CreateTable
+CREATE TABLE "AccessEvent" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "created_at" DATETIME NOT NULL,
+    "user_id" INTEGER,
+    "email" TEXT,
+    "door_id" INTEGER,
+    "action" TEXT NOT NULL,
+    "result" TEXT NOT NULL,
+    "source_ip" TEXT,
+    "reason" TEXT
+);
+
+-- CreateIndex
+CREATE INDEX "AccessEvent_door_id_created_at_idx" ON "AccessEvent"("door_id", "created_at");
+
+-- CreateIndex
+CREATE INDEX "AccessEvent_user_id_created_at_idx" ON "AccessEvent"("user_id", "created_at");
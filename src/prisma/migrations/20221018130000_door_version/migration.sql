@@ -0,0 +1,5 @@
+-- AlterTable
+ALTER TABLE "Door" ADD COLUMN "version" INTEGER NOT NULL DEFAULT 0;
+
+-- Doors were stored as "close" before explicit actions
+UPDATE "Door" SET "state" = 'closed' WHERE "state" = 'close';
@@ -0,0 +1,3 @@
+-- AlterTable
+ALTER TABLE "Door" ADD COLUMN "relock_after" INTEGER;
+ALTER TABLE "Door" ADD COLUMN "relock_at" DATETIME;
@@ -0,0 +1,8 @@
+-- CreateTable
+CREATE TABLE "DoorbellRing" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "created_at" DATETIME NOT NULL,
+    "door_id" INTEGER,
+    "source" TEXT NOT NULL,
+    "notified" BOOLEAN NOT NULL
+);
@@ -0,0 +1,17 @@
+-- CreateTable
+CREATE TABLE "Session" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "user_id" INTEGER NOT NULL,
+    "refresh_hash" TEXT NOT NULL,
+    "created_at" DATETIME NOT NULL,
+    "last_used_at" DATETIME NOT NULL,
+    "expires_at" DATETIME NOT NULL,
+    "user_agent" TEXT,
+    "source_ip" TEXT
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "Session_refresh_hash_key" ON "Session"("refresh_hash");
+
+-- CreateIndex
+CREATE INDEX "Session_user_id_idx" ON "Session"("user_id");
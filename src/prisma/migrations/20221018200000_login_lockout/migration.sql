@@ -0,0 +1,4 @@
+-- AlterTable
+ALTER TABLE "User" ADD COLUMN "failed_logins" INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE "User" ADD COLUMN "last_failed_login" DATETIME;
+ALTER TABLE "User" ADD COLUMN "locked_until" DATETIME;
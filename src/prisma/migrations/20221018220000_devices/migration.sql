@@ -0,0 +1,42 @@
+-- CreateTable
+CREATE TABLE "Device" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "user_id" INTEGER NOT NULL,
+    "platform" TEXT NOT NULL,
+    "token" TEXT NOT NULL,
+    "app_version" TEXT,
+    "created_at" DATETIME NOT NULL,
+    "last_seen" DATETIME NOT NULL
+);
+
+-- MoveData
+INSERT INTO "Device" ("user_id", "platform", "token", "created_at", "last_seen")
+SELECT "id", COALESCE("device_platform", 'ios'), "device_token", CAST(strftime('%s', 'now') AS INTEGER) * 1000, CAST(strftime('%s', 'now') AS INTEGER) * 1000
+FROM "User" WHERE "device_token" IS NOT NULL;
+
+-- RedefineTables
+PRAGMA foreign_keys=OFF;
+CREATE TABLE "new_User" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "email" TEXT NOT NULL,
+    "name" TEXT NOT NULL,
+    "password" TEXT NOT NULL,
+    "role" TEXT NOT NULL,
+    "disabled" BOOLEAN NOT NULL DEFAULT false,
+    "must_change_password" BOOLEAN NOT NULL DEFAULT false,
+    "failed_logins" INTEGER NOT NULL DEFAULT 0,
+    "last_failed_login" DATETIME,
+    "locked_until" DATETIME
+);
+INSERT INTO "new_User" ("id", "email", "name", "password", "role", "disabled", "must_change_password", "failed_logins", "last_failed_login", "locked_until") SELECT "id", "email", "name", "password", "role", "disabled", "must_change_password", "failed_logins", "last_failed_login", "locked_until" FROM "User";
+DROP TABLE "User";
+ALTER TABLE "new_User" RENAME TO "User";
+CREATE UNIQUE INDEX "User_email_key" ON "User"("email");
+PRAGMA foreign_key_check;
+PRAGMA foreign_keys=ON;
+
+-- CreateIndex
+CREATE UNIQUE INDEX "Device_token_key" ON "Device"("token");
+
+-- CreateIndex
+CREATE INDEX "Device_user_id_idx" ON "Device"("user_id");
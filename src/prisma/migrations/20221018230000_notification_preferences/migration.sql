@@ -0,0 +1,17 @@
+-- CreateTable
+CREATE TABLE "NotificationPreference" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "user_id" INTEGER NOT NULL,
+    "door_events" BOOLEAN NOT NULL DEFAULT true,
+    "doorbell" BOOLEAN NOT NULL DEFAULT true,
+    "failed_attempts" BOOLEAN NOT NULL DEFAULT true,
+    "low_battery" BOOLEAN NOT NULL DEFAULT true,
+    "own_actions" BOOLEAN NOT NULL DEFAULT false,
+    "door_ids" TEXT,
+    "quiet_start" TEXT,
+    "quiet_end" TEXT,
+    "utc_offset" INTEGER NOT NULL DEFAULT 0
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "NotificationPreference_user_id_key" ON "NotificationPreference"("user_id");
@@ -0,0 +1,14 @@
+-- AlterTable
+ALTER TABLE "User" ADD COLUMN "locale" TEXT NOT NULL DEFAULT 'en';
+
+-- CreateTable
+CREATE TABLE "MessageTemplate" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "locale" TEXT NOT NULL,
+    "key" TEXT NOT NULL,
+    "body" TEXT NOT NULL,
+    "updated_at" DATETIME NOT NULL
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "MessageTemplate_locale_key_key" ON "MessageTemplate"("locale", "key");
@@ -0,0 +1,2 @@
+-- AlterTable
+ALTER TABLE "Door" ADD COLUMN "battery" INTEGER;
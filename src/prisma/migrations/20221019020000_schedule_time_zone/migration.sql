@@ -0,0 +1,2 @@
+-- AlterTable
+ALTER TABLE "AccessSchedule" ADD COLUMN "time_zone" TEXT;
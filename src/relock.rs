@@ -0,0 +1,171 @@
+use crate::{
+    access::{authorize, Permission},
+    actuator,
+    error::db_error,
+    events::{self, EventResult, NewEvent},
+    helpers::{door_id, find_door, set_door_state, spawn_notification},
+    middleware::ClaimsExt,
+    preferences::{Subject, Topic},
+    prisma::{
+        Door, DoorUpdateInput, DoorUpdateInputRelockAfter, DoorUpdateInputRelockAt,
+        DoorWhereUniqueInput, FindManyDoorArgs, UpdateOneDoorArgs,
+    },
+    templates::Message,
+    DoorAction, DoorState, Polling, RelockRequest, TideState,
+};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use std::sync::Arc;
+use tide::{Error as TideError, Request, StatusCode};
+
+async fn set_relock_at(
+    state: &TideState,
+    door: &Door,
+    relock_at: Option<DateTime<Utc>>,
+) -> tide::Result<Door> {
+    state
+        .prisma
+        .update_door::<Door>(UpdateOneDoorArgs {
+            data: DoorUpdateInput {
+                relock_at: Some(relock_at.map(DoorUpdateInputRelockAt::DateTime)),
+                ..Default::default()
+            },
+            filter: DoorWhereUniqueInput {
+                id: Some(door.id),
+                ..Default::default()
+            },
+        })
+        .await
+        .map_err(db_error)
+}
+
+/// Persists a relock deadline for a door that was just unlocked or opened and
+/// starts the timer. Doors without `relock_after` stay as they are.
+pub async fn schedule(state: Arc<TideState>, door: &Door) -> tide::Result<()> {
+    let seconds = match door.relock_after {
+        Some(seconds) if seconds > 0 => seconds,
+        _ => return Ok(()),
+    };
+
+    // whole seconds so the deadline compares equal after a database round trip
+    let deadline = Utc::now() + Duration::seconds(seconds as i64);
+    let deadline = Utc.timestamp(deadline.timestamp(), 0);
+    set_relock_at(&state, door, Some(deadline)).await?;
+
+    spawn(state, door.id, deadline);
+    Ok(())
+}
+
+const RETRY_AFTER: std::time::Duration = std::time::Duration::from_secs(60);
+
+fn spawn(state: Arc<TideState>, door_id: i32, deadline: DateTime<Utc>) {
+    let runtime = state.runtime.clone();
+    runtime.spawn(async move {
+        let wait = (deadline - Utc::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(wait).await;
+
+        // the deadline stays stored until the door is locked, so keep at it
+        while let Err(e) = relock(state.clone(), door_id, deadline).await {
+            println!("Auto-relock of door {} failed, retrying: {}", door_id, e);
+            tokio::time::sleep(RETRY_AFTER).await;
+        }
+    });
+}
+
+async fn relock(state: Arc<TideState>, door_id: i32, deadline: DateTime<Utc>) -> tide::Result<()> {
+    let door = find_door(&state, door_id).await?;
+    // read again once no user action can change the door underneath us
+    let mut actuator = actuator::claim(&state, &door).await?;
+    let door = find_door(&state, door_id).await?;
+
+    // a later unlock moved the deadline, or the door was relocked already
+    if door.relock_at != Some(deadline) {
+        return Ok(());
+    }
+    if DoorState::from_str(&door.state) == Ok(DoorState::Locked) {
+        set_relock_at(&state, &door, None).await?;
+        return Ok(());
+    }
+
+    actuator::drive(&state, &mut actuator, &door, DoorAction::Lock).await?;
+    let door = set_door_state(&state, &door, DoorAction::Lock.target()).await?;
+    // cleared only now, so a failed relock is retried and resumed after a restart
+    let door = set_relock_at(&state, &door, None).await?;
+    drop(actuator);
+
+    let event = NewEvent::new(DoorAction::Lock.to_string(), EventResult::Allowed)
+        .door(door.id)
+        .reason("auto-relock");
+    events::record(&state, event).await;
+
+    let message = Message::new("door.relocked").var("door", &door.name);
+    let subject = Subject::new(Topic::DoorEvents).door(door.id);
+    spawn_notification(state, subject, message);
+
+    Ok(())
+}
+
+/// Restarts timers for deadlines persisted before the server last stopped.
+/// Deadlines that already passed fire straight away.
+pub async fn resume(state: Arc<TideState>) -> tide::Result<()> {
+    let doors = state
+        .prisma
+        .doors::<Door>(FindManyDoorArgs {
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?;
+
+    for door in doors {
+        if let Some(deadline) = door.relock_at {
+            println!("Resuming auto-relock of {} at {}", door.name, deadline);
+            spawn(state.clone(), door.id, deadline);
+        }
+    }
+
+    Ok(())
+}
+
+/// Rejects a relock delay that isn't a positive number of seconds.
+pub fn validate_relock_after(relock_after: Option<i32>) -> tide::Result<()> {
+    match relock_after {
+        Some(seconds) if seconds <= 0 => Err(TideError::from_str(
+            StatusCode::BadRequest,
+            "relock_after must be a positive number of seconds",
+        )),
+        _ => Ok(()),
+    }
+}
+
+pub async fn set_relock(mut req: Request<Arc<TideState>>) -> tide::Result {
+    let relock_request = req.body_json::<RelockRequest>().await?;
+    let claims = req.claims()?;
+    let id = door_id(&req)?;
+    authorize(req.state(), &claims, id, Permission::Manage).await?;
+
+    validate_relock_after(relock_request.relock_after)?;
+
+    let door = find_door(req.state(), id).await?;
+    let door = req
+        .state()
+        .prisma
+        .update_door::<Door>(UpdateOneDoorArgs {
+            data: DoorUpdateInput {
+                relock_after: Some(
+                    relock_request
+                        .relock_after
+                        .map(DoorUpdateInputRelockAfter::Int),
+                ),
+                ..Default::default()
+            },
+            filter: DoorWhereUniqueInput {
+                id: Some(door.id),
+                ..Default::default()
+            },
+        })
+        .await
+        .map_err(|e| TideError::from_str(400, format!("Door invalid: {}", e)))?;
+
+    let mut res = tide::Response::new(StatusCode::Ok);
+    res.set_body(tide::Body::from_json(&Polling::from(door))?);
+    Ok(res)
+}
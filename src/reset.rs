@@ -0,0 +1,72 @@
+use crate::{
+    error::db_error,
+    prisma::{
+        DeleteManyPasswordResetTokenArgs, PasswordResetToken, PasswordResetTokenCreateInput,
+        PasswordResetTokenWhereInput, PasswordResetTokenWhereInputUserId,
+        PasswordResetTokenWhereUniqueInput, User,
+    },
+    utils::{random_token, sha256_hex},
+    TideState,
+};
+use chrono::{Duration, Utc};
+use tide::{Error as TideError, StatusCode};
+
+const TOKEN_BYTES: usize = 32;
+
+/// Creates a reset token for `user` that expires after `lifetime` and returns
+/// it in plain text, to be emailed and then forgotten. Invites use the same
+/// tokens with a longer lifetime.
+pub async fn issue(state: &TideState, user: &User, lifetime: Duration) -> tide::Result<String> {
+    let token = random_token(TOKEN_BYTES);
+    let now = Utc::now();
+
+    state
+        .prisma
+        .create_password_reset_token::<PasswordResetToken>(PasswordResetTokenCreateInput {
+            user_id: user.id,
+            token_hash: sha256_hex(&token),
+            created_at: now,
+            expires_at: now + lifetime,
+        })
+        .await
+        .map_err(db_error)?;
+
+    Ok(token)
+}
+
+/// Consumes `token`, returning the id of the user it was issued to. A token
+/// works once: it is deleted here along with every other token of that user.
+pub async fn redeem(state: &TideState, token: &str) -> tide::Result<i32> {
+    let invalid = || {
+        TideError::from_str(
+            StatusCode::BadRequest,
+            "Reset link is invalid or has expired",
+        )
+    };
+
+    // deleting is what claims the token, so two requests cannot both use it
+    let claimed = state
+        .prisma
+        .delete_password_reset_token::<PasswordResetToken>(PasswordResetTokenWhereUniqueInput {
+            token_hash: Some(sha256_hex(token)),
+            ..Default::default()
+        })
+        .await
+        .map_err(|_e| invalid())?;
+
+    state
+        .prisma
+        .delete_many_password_reset_tokens(DeleteManyPasswordResetTokenArgs {
+            filter: Some(PasswordResetTokenWhereInput {
+                user_id: Some(PasswordResetTokenWhereInputUserId::Int(claimed.user_id)),
+                ..Default::default()
+            }),
+        })
+        .await
+        .map_err(db_error)?;
+
+    if claimed.expires_at < Utc::now() {
+        return Err(invalid());
+    }
+    Ok(claimed.user_id)
+}
@@ -0,0 +1,423 @@
+use crate::{
+    access::{authorize, Permission},
+    auth::{Claims, Role},
+    error::db_error,
+    helpers::{door_id, find_door},
+    middleware::ClaimsExt,
+    prisma::{
+        AccessSchedule, AccessScheduleCreateInput, AccessScheduleWhereInput,
+        AccessScheduleWhereInputDoorId, AccessScheduleWhereInputUserId,
+        AccessScheduleWhereUniqueInput, Door, FindManyAccessScheduleArgs, User,
+        UserWhereUniqueInput,
+    },
+    ScheduleRequest, TideState,
+};
+use chrono::{
+    DateTime, Datelike, Duration, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, Utc, Weekday,
+};
+use chrono_tz::Tz;
+use std::{fmt, sync::Arc};
+use tide::{Body, Error as TideError, Request, StatusCode};
+
+/// Days a window applies to: a weekly rule or a single calendar date.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Days {
+    Weekly(Vec<Weekday>),
+    Once(NaiveDate),
+}
+
+/// The zone a window's wall-clock times are read in.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Zone {
+    /// An IANA zone such as `Europe/Berlin`, following its daylight saving changes.
+    Named(Tz),
+    /// A fixed offset, kept for schedules stored before zones were named.
+    Fixed(FixedOffset),
+}
+
+impl Zone {
+    pub fn named(name: &str) -> std::result::Result<Zone, String> {
+        name.parse::<Tz>()
+            .map(Zone::Named)
+            .map_err(|_e| format!("unknown time zone {}", name))
+    }
+
+    /// `utc_offset` is in minutes east of UTC.
+    pub fn from_offset(utc_offset: i32) -> std::result::Result<Zone, String> {
+        if !(-MAX_OFFSET_MINUTES..=MAX_OFFSET_MINUTES).contains(&utc_offset) {
+            return Err(format!("invalid utc offset {}", utc_offset));
+        }
+        FixedOffset::east_opt(utc_offset * 60)
+            .map(Zone::Fixed)
+            .ok_or_else(|| format!("invalid utc offset {}", utc_offset))
+    }
+
+    fn local(&self, now: DateTime<Utc>) -> NaiveDateTime {
+        match self {
+            Zone::Named(tz) => now.with_timezone(tz).naive_local(),
+            Zone::Fixed(offset) => now.with_timezone(offset).naive_local(),
+        }
+    }
+}
+
+impl fmt::Display for Zone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Zone::Named(tz) => write!(f, "{}", tz.name()),
+            Zone::Fixed(offset) => write!(f, "UTC{}", offset),
+        }
+    }
+}
+
+/// Real-world offsets stay within UTC-12:00 to UTC+14:00.
+const MAX_OFFSET_MINUTES: i32 = 14 * 60;
+
+/// A wall-clock access window evaluated in a time zone.
+/// Windows whose end is not after their start run past midnight.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Window {
+    pub days: Days,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub zone: Zone,
+}
+
+impl Window {
+    pub fn parse(schedule: &AccessSchedule) -> std::result::Result<Window, String> {
+        Window::from_parts(
+            schedule.weekdays.as_deref(),
+            schedule.date.as_deref(),
+            &schedule.start_time,
+            &schedule.end_time,
+            match &schedule.time_zone {
+                Some(name) => Zone::named(name)?,
+                None => Zone::from_offset(schedule.utc_offset)?,
+            },
+        )
+    }
+
+    pub fn from_parts(
+        weekdays: Option<&str>,
+        date: Option<&str>,
+        start_time: &str,
+        end_time: &str,
+        zone: Zone,
+    ) -> std::result::Result<Window, String> {
+        let days = match (weekdays, date) {
+            (Some(weekdays), None) => Days::Weekly(parse_weekdays(weekdays)?),
+            (None, Some(date)) => Days::Once(
+                NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                    .map_err(|_e| format!("invalid date {}", date))?,
+            ),
+            _ => Err("exactly one of weekdays or date must be set".to_string())?,
+        };
+        Ok(Window {
+            days,
+            start: parse_time(start_time)?,
+            end: parse_time(end_time)?,
+            zone,
+        })
+    }
+
+    pub fn contains(&self, now: DateTime<Utc>) -> bool {
+        let local = self.zone.local(now);
+        let (date, time) = (local.date(), local.time());
+
+        if self.start < self.end {
+            return self.applies_on(date) && time >= self.start && time < self.end;
+        }
+
+        // overnight window: the part after midnight belongs to the previous day
+        (self.applies_on(date) && time >= self.start)
+            || (self.applies_on(date - Duration::days(1)) && time < self.end)
+    }
+
+    fn applies_on(&self, date: NaiveDate) -> bool {
+        match &self.days {
+            Days::Weekly(weekdays) => weekdays.contains(&date.weekday()),
+            Days::Once(once) => *once == date,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let days = match &self.days {
+            Days::Weekly(weekdays) => weekdays
+                .iter()
+                .map(|day| day.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+            Days::Once(date) => date.to_string(),
+        };
+        format!(
+            "{} {}-{} ({})",
+            days,
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M"),
+            self.zone
+        )
+    }
+}
+
+fn parse_weekdays(weekdays: &str) -> std::result::Result<Vec<Weekday>, String> {
+    weekdays
+        .split(',')
+        .map(|day| {
+            day.trim()
+                .parse::<Weekday>()
+                .map_err(|_e| format!("invalid weekday {}", day))
+        })
+        .collect()
+}
+
+fn parse_time(time: &str) -> std::result::Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(time, "%H:%M").map_err(|_e| format!("invalid time {}", time))
+}
+
+/// Returns the reason access is denied at `now`, or `Ok` when any window is open.
+/// An empty schedule list places no restriction.
+pub fn check(windows: &[Window], now: DateTime<Utc>) -> std::result::Result<(), String> {
+    if windows.is_empty() || windows.iter().any(|window| window.contains(now)) {
+        return Ok(());
+    }
+
+    let allowed = windows
+        .iter()
+        .map(Window::describe)
+        .collect::<Vec<_>>()
+        .join("; ");
+    Err(format!("outside of scheduled access hours: {}", allowed))
+}
+
+async fn schedules_for(
+    state: &TideState,
+    filter: AccessScheduleWhereInput,
+) -> tide::Result<Vec<AccessSchedule>> {
+    state
+        .prisma
+        .access_schedules::<AccessSchedule>(FindManyAccessScheduleArgs {
+            filter: Some(filter),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)
+}
+
+/// Fails with 403 and the denial reason when `claims` are outside their schedule for `door`.
+pub async fn enforce(state: &TideState, claims: &Claims, door: &Door) -> tide::Result<()> {
+    if claims.role == Role::Admin {
+        return Ok(());
+    }
+
+    let schedules = schedules_for(
+        state,
+        AccessScheduleWhereInput {
+            user_id: Some(AccessScheduleWhereInputUserId::Int(claims.sub as i32)),
+            door_id: Some(AccessScheduleWhereInputDoorId::Int(door.id)),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    // a rule that can't be read must not widen access, so deny until it's fixed
+    let windows = schedules
+        .iter()
+        .map(|schedule| {
+            Window::parse(schedule)
+                .map_err(|e| format!("schedule {} can't be evaluated ({})", schedule.id, e))
+        })
+        .collect::<std::result::Result<Vec<_>, String>>();
+
+    windows
+        .and_then(|windows| check(&windows, Utc::now()))
+        .map_err(|reason| {
+            TideError::from_str(
+                StatusCode::Forbidden,
+                format!("Access to {} denied: {}", door.name, reason),
+            )
+        })
+}
+
+pub async fn list_schedules(req: Request<Arc<TideState>>) -> tide::Result {
+    let claims = req.claims()?;
+    let door_id = door_id(&req)?;
+    authorize(req.state(), &claims, door_id, Permission::Manage).await?;
+
+    let schedules = schedules_for(
+        req.state(),
+        AccessScheduleWhereInput {
+            door_id: Some(AccessScheduleWhereInputDoorId::Int(door_id)),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    let mut res = tide::Response::new(StatusCode::Ok);
+    res.set_body(Body::from_json(&schedules)?);
+    Ok(res)
+}
+
+pub async fn create_schedule(mut req: Request<Arc<TideState>>) -> tide::Result {
+    let schedule_request = req.body_json::<ScheduleRequest>().await?;
+    let claims = req.claims()?;
+    let door_id = door_id(&req)?;
+    authorize(req.state(), &claims, door_id, Permission::Manage).await?;
+    let door = find_door(req.state(), door_id).await?;
+    req.state()
+        .prisma
+        .user::<User>(UserWhereUniqueInput {
+            id: Some(schedule_request.user_id),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| TideError::from_str(StatusCode::NotFound, "User not found"))?;
+
+    let utc_offset = schedule_request.utc_offset.unwrap_or(0);
+    let zone = match (&schedule_request.time_zone, schedule_request.utc_offset) {
+        (Some(_), Some(_)) => Err("set either time_zone or utc_offset".to_string()),
+        (Some(name), None) => Zone::named(name),
+        (None, _) => Zone::from_offset(utc_offset),
+    };
+
+    // reject rules that could never be evaluated
+    zone.and_then(|zone| {
+        Window::from_parts(
+            schedule_request.weekdays.as_deref(),
+            schedule_request.date.as_deref(),
+            &schedule_request.start_time,
+            &schedule_request.end_time,
+            zone,
+        )
+    })
+    .map_err(|e| TideError::from_str(StatusCode::BadRequest, format!("Schedule invalid: {}", e)))?;
+
+    let schedule = req
+        .state()
+        .prisma
+        .create_access_schedule::<AccessSchedule>(AccessScheduleCreateInput {
+            user_id: schedule_request.user_id,
+            door_id: door.id,
+            weekdays: schedule_request.weekdays,
+            date: schedule_request.date,
+            start_time: schedule_request.start_time,
+            end_time: schedule_request.end_time,
+            utc_offset,
+            time_zone: schedule_request.time_zone,
+        })
+        .await
+        .map_err(|e| TideError::from_str(400, format!("Schedule invalid: {}", e)))?;
+
+    let mut res = tide::Response::new(StatusCode::Created);
+    res.set_body(Body::from_json(&schedule)?);
+    Ok(res)
+}
+
+pub async fn delete_schedule(req: Request<Arc<TideState>>) -> tide::Result {
+    let claims = req.claims()?;
+    let door_id = door_id(&req)?;
+    authorize(req.state(), &claims, door_id, Permission::Manage).await?;
+
+    let schedule_id = req.param("schedule_id")?.parse::<i32>().map_err(|e| {
+        TideError::from_str(
+            StatusCode::BadRequest,
+            format!("Invalid schedule id: {}", e),
+        )
+    })?;
+
+    let schedule = req
+        .state()
+        .prisma
+        .access_schedule::<AccessSchedule>(AccessScheduleWhereUniqueInput {
+            id: Some(schedule_id),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?
+        .filter(|schedule| schedule.door_id == door_id)
+        .ok_or_else(|| TideError::from_str(StatusCode::NotFound, "Schedule not found"))?;
+
+    req.state()
+        .prisma
+        .delete_access_schedule::<AccessSchedule>(AccessScheduleWhereUniqueInput {
+            id: Some(schedule.id),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?;
+
+    Ok(tide::Response::new(StatusCode::NoContent))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn window(days: Days, start: &str, end: &str, offset_hours: i32) -> Window {
+        Window {
+            days,
+            start: parse_time(start).unwrap(),
+            end: parse_time(end).unwrap(),
+            zone: Zone::from_offset(offset_hours * 60).unwrap(),
+        }
+    }
+
+    #[test]
+    fn weekday_office_hours() {
+        let weekdays = parse_weekdays("mon,tue,wed,thu,fri").unwrap();
+        let office = window(Days::Weekly(weekdays), "08:00", "18:00", 1);
+
+        // Monday 2022-10-17 07:30 UTC is 08:30 at UTC+1
+        assert!(office.contains(Utc.ymd(2022, 10, 17).and_hms(7, 30, 0)));
+        // 17:30 UTC is 18:30 local, after hours
+        assert!(!office.contains(Utc.ymd(2022, 10, 17).and_hms(17, 30, 0)));
+        // Saturday
+        assert!(!office.contains(Utc.ymd(2022, 10, 22).and_hms(10, 0, 0)));
+    }
+
+    #[test]
+    fn one_off_and_overnight_windows() {
+        let saturday = NaiveDate::from_ymd(2022, 10, 22);
+        let once = window(Days::Once(saturday), "10:00", "14:00", 0);
+        assert!(once.contains(Utc.ymd(2022, 10, 22).and_hms(11, 0, 0)));
+        assert!(!once.contains(Utc.ymd(2022, 10, 29).and_hms(11, 0, 0)));
+
+        let night = window(Days::Weekly(vec![Weekday::Fri]), "22:00", "06:00", 0);
+        assert!(night.contains(Utc.ymd(2022, 10, 21).and_hms(23, 0, 0)));
+        assert!(night.contains(Utc.ymd(2022, 10, 22).and_hms(5, 0, 0)));
+        assert!(!night.contains(Utc.ymd(2022, 10, 22).and_hms(23, 0, 0)));
+    }
+
+    #[test]
+    fn named_zones_follow_daylight_saving() {
+        let berlin = Window {
+            zone: Zone::named("Europe/Berlin").unwrap(),
+            ..window(Days::Weekly(vec![Weekday::Mon]), "08:00", "18:00", 0)
+        };
+
+        // 08:30 local is 06:30 UTC in summer time and 07:30 UTC in winter
+        assert!(berlin.contains(Utc.ymd(2022, 10, 17).and_hms(6, 30, 0)));
+        assert!(!berlin.contains(Utc.ymd(2022, 11, 7).and_hms(6, 30, 0)));
+        assert!(berlin.contains(Utc.ymd(2022, 11, 7).and_hms(7, 30, 0)));
+        assert!(berlin.describe().contains("(Europe/Berlin)"));
+    }
+
+    #[test]
+    fn rejects_bad_zones() {
+        assert!(Zone::named("Mars/Olympus_Mons").is_err());
+        assert!(Zone::from_offset(14 * 60).is_ok());
+        assert!(Zone::from_offset(15 * 60).is_err());
+        assert!(Zone::from_offset(i32::MAX).is_err());
+        assert!(Zone::from_offset(i32::MIN).is_err());
+    }
+
+    #[test]
+    fn check_reports_denial_reason() {
+        let now = Utc.ymd(2022, 10, 22).and_hms(11, 0, 0);
+        assert!(check(&[], now).is_ok());
+
+        let weekdays = window(Days::Weekly(vec![Weekday::Mon]), "08:00", "18:00", 0);
+        let reason = check(&[weekdays], now).unwrap_err();
+        assert!(reason.contains("Mon 08:00-18:00"));
+    }
+}
@@ -0,0 +1,293 @@
+use crate::{
+    access::require_admin,
+    auth::{create_jwt, Claims, Role},
+    error::db_error,
+    events::{self, EventResult, NewEvent},
+    middleware::ClaimsExt,
+    prisma::{
+        DeleteManySessionArgs, FindManySessionArgs, Session, SessionCreateInput,
+        SessionOrderByInput, SessionUpdateManyMutationInput,
+        SessionUpdateManyMutationInputExpiresAt, SessionUpdateManyMutationInputLastUsedAt,
+        SessionUpdateManyMutationInputRefreshHash, SessionWhereInput, SessionWhereInputId,
+        SessionWhereInputRefreshHash, SessionWhereInputUserId, SessionWhereUniqueInput, SortOrder,
+        UpdateManySessionArgs, User, UserWhereUniqueInput,
+    },
+    utils::{random_token, sha256_hex},
+    LoginResponse, RefreshRequest, TideState,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::sync::Arc;
+use tide::{Body, Error as TideError, Request, StatusCode};
+
+const REFRESH_TOKEN_BYTES: usize = 32;
+
+fn unauthorized(message: &'static str) -> TideError {
+    TideError::from_str(StatusCode::Unauthorized, message)
+}
+
+fn tokens(
+    state: &TideState,
+    user: &User,
+    sid: i32,
+    refresh_token: String,
+) -> tide::Result<LoginResponse> {
+    let ttl = Duration::minutes(state.config.access_token_minutes);
+    let token = create_jwt(
+        user.id as usize,
+        &Role::from_str(&user.role),
+        user.email.clone(),
+        sid,
+        ttl,
+        state.config.jwt_secret.as_bytes(),
+    )
+    .map_err(|e| tide::http::Error::from(e))?;
+
+    Ok(LoginResponse {
+        token,
+        refresh_token,
+        expires_in: ttl.num_seconds(),
+    })
+}
+
+/// Opens a session for `user` after a successful login.
+pub async fn start(req: &Request<Arc<TideState>>, user: &User) -> tide::Result<LoginResponse> {
+    let state = req.state();
+    let refresh_token = random_token(REFRESH_TOKEN_BYTES);
+    let now = Utc::now();
+
+    let session = state
+        .prisma
+        .create_session::<Session>(SessionCreateInput {
+            user_id: user.id,
+            refresh_hash: sha256_hex(&refresh_token),
+            created_at: now,
+            last_used_at: now,
+            expires_at: now + Duration::days(state.config.refresh_token_days),
+            user_agent: req
+                .header("User-Agent")
+                .map(|agent| agent.as_str().to_string()),
+            source_ip: req.remote().map(|remote| remote.to_string()),
+        })
+        .await
+        .map_err(db_error)?;
+
+    tokens(state, user, session.id, refresh_token)
+}
+
+/// Fails with 401 once the session behind `claims` has been revoked or has expired.
+pub async fn check(state: &TideState, claims: &Claims) -> tide::Result<()> {
+    let session = state
+        .prisma
+        .session::<Session>(SessionWhereUniqueInput {
+            id: Some(claims.sid),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?;
+
+    match session {
+        Some(session)
+            if session.user_id as usize == claims.sub && session.expires_at > Utc::now() =>
+        {
+            Ok(())
+        }
+        _ => Err(unauthorized("Session has ended, log in again")),
+    }
+}
+
+/// Ends every session of `user_id`, e.g. after a password reset.
+pub async fn revoke_all(state: &TideState, user_id: i32) -> tide::Result<i64> {
+    let revoked = state
+        .prisma
+        .delete_many_sessions(DeleteManySessionArgs {
+            filter: Some(SessionWhereInput {
+                user_id: Some(SessionWhereInputUserId::Int(user_id)),
+                ..Default::default()
+            }),
+        })
+        .await
+        .map_err(db_error)?;
+    Ok(revoked.count)
+}
+
+/// Trades a refresh token for a new access token and a new refresh token. The
+/// old refresh token stops working, and an unused session expires after
+/// `refresh_token_days`.
+pub async fn refresh_handler(mut req: Request<Arc<TideState>>) -> tide::Result {
+    let request = req.body_json::<RefreshRequest>().await?;
+    let state = req.state();
+    let refresh_hash = sha256_hex(&request.refresh_token);
+    let now = Utc::now();
+
+    let session = state
+        .prisma
+        .session::<Session>(SessionWhereUniqueInput {
+            refresh_hash: Some(refresh_hash.clone()),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?
+        .filter(|session| session.expires_at > now)
+        .ok_or_else(|| unauthorized("Refresh token is invalid or has expired"))?;
+
+    let user = state
+        .prisma
+        .user::<User>(UserWhereUniqueInput {
+            id: Some(session.user_id),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?;
+    let user = match user {
+        Some(user) if !user.disabled => user,
+        _ => {
+            revoke_all(state, session.user_id).await?;
+            return Err(unauthorized("This account has been disabled"));
+        }
+    };
+
+    // swapping the hash only succeeds for the first of two concurrent refreshes
+    let refresh_token = random_token(REFRESH_TOKEN_BYTES);
+    let rotated = state
+        .prisma
+        .update_many_sessions(UpdateManySessionArgs {
+            data: SessionUpdateManyMutationInput {
+                refresh_hash: Some(SessionUpdateManyMutationInputRefreshHash::String(
+                    sha256_hex(&refresh_token),
+                )),
+                last_used_at: Some(SessionUpdateManyMutationInputLastUsedAt::DateTime(now)),
+                expires_at: Some(SessionUpdateManyMutationInputExpiresAt::DateTime(
+                    now + Duration::days(state.config.refresh_token_days),
+                )),
+                ..Default::default()
+            },
+            filter: Some(SessionWhereInput {
+                id: Some(SessionWhereInputId::Int(session.id)),
+                refresh_hash: Some(SessionWhereInputRefreshHash::String(refresh_hash)),
+                ..Default::default()
+            }),
+        })
+        .await
+        .map_err(db_error)?;
+    if rotated.count == 0 {
+        return Err(unauthorized("Refresh token is invalid or has expired"));
+    }
+
+    let mut res = tide::Response::new(StatusCode::Ok);
+    res.set_body(Body::from_json(&tokens(
+        state,
+        &user,
+        session.id,
+        refresh_token,
+    )?)?);
+    Ok(res)
+}
+
+async fn delete_session(state: &TideState, id: i32) -> tide::Result<()> {
+    state
+        .prisma
+        .delete_many_sessions(DeleteManySessionArgs {
+            filter: Some(SessionWhereInput {
+                id: Some(SessionWhereInputId::Int(id)),
+                ..Default::default()
+            }),
+        })
+        .await
+        .map_err(db_error)?;
+    Ok(())
+}
+
+/// Ends the session the request was made with.
+pub async fn logout_handler(req: Request<Arc<TideState>>) -> tide::Result {
+    let claims = req.claims()?;
+    delete_session(req.state(), claims.sid).await?;
+
+    let event = NewEvent::new("logout", EventResult::Allowed)
+        .source(&req)
+        .user(Some(claims.sub as i32), Some(claims.email));
+    events::record(req.state(), event).await;
+
+    Ok(tide::Response::new(StatusCode::NoContent))
+}
+
+#[derive(Serialize)]
+pub struct SessionResponse {
+    pub id: i32,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub user_agent: Option<String>,
+    pub source_ip: Option<String>,
+    /// Whether this is the session making the request.
+    pub current: bool,
+}
+
+/// Lists the caller's sessions, most recently used first.
+pub async fn list_sessions(req: Request<Arc<TideState>>) -> tide::Result {
+    let claims = req.claims()?;
+
+    let sessions = req
+        .state()
+        .prisma
+        .sessions::<Session>(FindManySessionArgs {
+            filter: Some(SessionWhereInput {
+                user_id: Some(SessionWhereInputUserId::Int(claims.sub as i32)),
+                ..Default::default()
+            }),
+            order_by: Some(vec![SessionOrderByInput {
+                last_used_at: Some(SortOrder::Desc),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?
+        .into_iter()
+        .map(|session| SessionResponse {
+            current: session.id == claims.sid,
+            id: session.id,
+            created_at: session.created_at,
+            last_used_at: session.last_used_at,
+            expires_at: session.expires_at,
+            user_agent: session.user_agent,
+            source_ip: session.source_ip,
+        })
+        .collect::<Vec<_>>();
+
+    let mut res = tide::Response::new(StatusCode::Ok);
+    res.set_body(Body::from_json(&sessions)?);
+    Ok(res)
+}
+
+/// Revokes one session, e.g. of a lost phone. Admins may revoke anyone's.
+pub async fn revoke_session(req: Request<Arc<TideState>>) -> tide::Result {
+    let claims = req.claims()?;
+    let id = req.param("session_id")?.parse::<i32>().map_err(|e| {
+        TideError::from_str(StatusCode::BadRequest, format!("Invalid session id: {}", e))
+    })?;
+
+    let session = req
+        .state()
+        .prisma
+        .session::<Session>(SessionWhereUniqueInput {
+            id: Some(id),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| TideError::from_str(StatusCode::NotFound, "Session not found"))?;
+    if session.user_id as usize != claims.sub {
+        require_admin(&claims)?;
+    }
+
+    delete_session(req.state(), session.id).await?;
+
+    let event = NewEvent::new("session_revoked", EventResult::Allowed)
+        .source(&req)
+        .user(Some(session.user_id), None)
+        .reason(format!("revoked by {}", claims.email));
+    events::record(req.state(), event).await;
+
+    Ok(tide::Response::new(StatusCode::NoContent))
+}
@@ -0,0 +1,198 @@
+use crate::{
+    auth::Role,
+    error::{db_error, Error},
+    events::{self, EventResult, NewEvent},
+    prisma::{
+        FindFirstUserArgs, UpdateOneUserArgs, User, UserCreateInput, UserUpdateInput,
+        UserUpdateInputDisabled, UserWhereInput, UserWhereInputDisabled, UserWhereInputRole,
+        UserWhereUniqueInput,
+    },
+    session,
+    users::parse_name,
+    utils::{check_email, check_password, random_token, sha256_hex},
+    SetupRequest, TideState,
+};
+use futures::lock::Mutex;
+use std::sync::Arc;
+use tide::{Body, Error as TideError, Request, StatusCode};
+
+const SETUP_TOKEN_BYTES: usize = 16;
+
+/// Accounts that earlier versions created on every fresh database, all with
+/// the same published password.
+const SEEDED_ACCOUNTS: [&str; 5] = [
+    "seunlanlege@gmail.com",
+    "example@gmail.com",
+    "oluwashinabajo@gmail.com",
+    "jummyfola013@gmail.com",
+    "debbiebajo@gmail.com",
+];
+const SEEDED_PASSWORD: &str = "Password@123";
+
+/// First-run state. While the database has no enabled admin, whoever holds the setup
+/// token may create the first one through `/setup`; afterwards it is disabled.
+#[derive(Default)]
+pub struct Setup {
+    /// Hash of the setup token, `None` once an admin exists.
+    token_hash: Mutex<Option<String>>,
+}
+
+async fn admin_exists(state: &TideState) -> tide::Result<bool> {
+    let admin = state
+        .prisma
+        .first_user::<User>(FindFirstUserArgs {
+            filter: Some(UserWhereInput {
+                role: Some(UserWhereInputRole::String(Role::Admin.as_str().to_string())),
+                disabled: Some(UserWhereInputDisabled::Boolean(false)),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?;
+    Ok(admin.is_some())
+}
+
+fn completed() -> TideError {
+    TideError::from_str(StatusCode::NotFound, "Setup has already been completed")
+}
+
+/// Disables the seeded accounts whose owners never changed the published
+/// password, and ends their sessions.
+async fn retire_seeded_accounts(state: &TideState) -> tide::Result<()> {
+    for email in SEEDED_ACCOUNTS {
+        let user = state
+            .prisma
+            .user::<User>(UserWhereUniqueInput {
+                email: Some(email.to_string()),
+                ..Default::default()
+            })
+            .await
+            .map_err(db_error)?;
+        let user = match user {
+            Some(user) if !user.disabled => user,
+            _ => continue,
+        };
+        let seeded = state
+            .hasher
+            .verify(SEEDED_PASSWORD, &user.password)
+            .map_or(false, |verification| verification.matches());
+        if !seeded {
+            continue;
+        }
+
+        state
+            .prisma
+            .update_user::<User>(UpdateOneUserArgs {
+                data: UserUpdateInput {
+                    disabled: Some(UserUpdateInputDisabled::Boolean(true)),
+                    ..Default::default()
+                },
+                filter: UserWhereUniqueInput {
+                    id: Some(user.id),
+                    ..Default::default()
+                },
+            })
+            .await
+            .map_err(db_error)?;
+        session::revoke_all(state, user.id).await?;
+
+        let event = NewEvent::new("user_updated", EventResult::Allowed)
+            .user(Some(user.id), Some(user.email.clone()))
+            .reason("disabled: still had the seeded default password");
+        events::record(state, event).await;
+        println!(
+            "Disabled {}, which still had the seeded default password",
+            user.email
+        );
+    }
+    Ok(())
+}
+
+/// Retires the seeded accounts, then enables `/setup` when no enabled admin is
+/// left, with the configured token or else a fresh one printed to the console.
+pub async fn prepare(state: &TideState) -> tide::Result<()> {
+    retire_seeded_accounts(state).await?;
+    if admin_exists(state).await? {
+        return Ok(());
+    }
+
+    let token = match &state.config.setup_token {
+        Some(token) => {
+            println!(
+                "No admin account yet, create one with POST /setup and the configured setup_token"
+            );
+            token.clone()
+        }
+        None => {
+            let token = random_token(SETUP_TOKEN_BYTES);
+            println!(
+                "No admin account yet, create one with POST /setup and the setup token {}",
+                token
+            );
+            token
+        }
+    };
+    *state.setup.token_hash.lock().await = Some(sha256_hex(&token));
+    Ok(())
+}
+
+/// Creates the first admin and logs them in. Works once per server start and
+/// only while no admin exists.
+pub async fn setup_handler(mut req: Request<Arc<TideState>>) -> tide::Result {
+    let request = req.body_json::<SetupRequest>().await?;
+    let state = req.state();
+
+    // held until the admin exists, so a token cannot be used twice
+    let mut token_hash = state.setup.token_hash.lock().await;
+    let expected = token_hash.as_deref().ok_or_else(completed)?;
+    if !openssl::memcmp::eq(expected.as_bytes(), sha256_hex(&request.token).as_bytes()) {
+        let event = NewEvent::new("setup", EventResult::Denied)
+            .source(&req)
+            .reason("wrong setup token");
+        events::record(state, event).await;
+        return Err(Error::WrongCredentialsError.into_tide());
+    }
+    // e.g. created with `user add --admin` while the server was running
+    if admin_exists(state).await? {
+        *token_hash = None;
+        return Err(completed());
+    }
+
+    let email = check_email(&request.email).map_err(Error::into_tide)?;
+    let name = parse_name(&request.name)?;
+    check_password(&request.password).map_err(Error::into_tide)?;
+
+    let user = state
+        .prisma
+        .create_user::<User>(UserCreateInput {
+            email,
+            name,
+            password: state
+                .hasher
+                .hash(&request.password)
+                .map_err(|e| Error::from(e).into_tide())?,
+            role: Role::Admin.as_str().to_string(),
+            locale: state.config.locale.clone(),
+            disabled: false,
+            must_change_password: false,
+            failed_logins: 0,
+            last_failed_login: None,
+            locked_until: None,
+        })
+        .await
+        .map_err(db_error)?;
+    *token_hash = None;
+    drop(token_hash);
+
+    let event = NewEvent::new("setup", EventResult::Allowed)
+        .source(&req)
+        .user(Some(user.id), Some(user.email.clone()));
+    events::record(state, event).await;
+
+    let tokens = session::start(&req, &user).await?;
+
+    let mut res = tide::Response::new(StatusCode::Created);
+    res.set_body(Body::from_json(&tokens)?);
+    Ok(res)
+}
@@ -0,0 +1,581 @@
+use crate::{
+    error::{db_error, Error},
+    events::{self, EventResult, NewEvent},
+    mail::Email,
+    middleware::ClaimsExt,
+    notify::Notification,
+    prisma::{
+        DeleteManyMessageTemplateArgs, FindFirstMessageTemplateArgs, FindManyMessageTemplateArgs,
+        MessageTemplate, MessageTemplateCreateInput, MessageTemplateUpdateInput,
+        MessageTemplateUpdateInputBody, MessageTemplateUpdateInputUpdatedAt,
+        MessageTemplateWhereInput, MessageTemplateWhereInputKey, MessageTemplateWhereInputLocale,
+        MessageTemplateWhereUniqueInput, UpdateOneMessageTemplateArgs,
+    },
+    TemplateRequest, TideState,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::{collections::HashMap, sync::Arc};
+use tide::{Body, Error as TideError, Request, StatusCode};
+
+/// Locale used where a user's own has no template.
+pub const FALLBACK_LOCALE: &str = "en";
+
+const MAX_TEMPLATE_LEN: usize = 10_000;
+
+/// Built-in templates as (locale, key, template). `{{name}}` placeholders are
+/// filled in when rendering, `{{time}}` always with the recipient's local time.
+const DEFAULTS: &[(&str, &str, &str)] = &[
+    ("en", "title", "Door"),
+    ("en", "door.lock", "{{user}} locked the {{door}}"),
+    ("en", "door.unlock", "{{user}} unlocked the {{door}}"),
+    ("en", "door.open", "{{user}} opened the {{door}}"),
+    ("en", "door.close", "{{user}} closed the {{door}}"),
+    ("en", "door.relocked", "{{door}} was locked automatically"),
+    (
+        "en",
+        "door.low_battery",
+        "The {{door}} battery is low ({{percent}}%)",
+    ),
+    ("en", "doorbell", "{{message}}"),
+    (
+        "en",
+        "failed_attempt",
+        "{{user}} was refused at the {{door}} at {{time}}",
+    ),
+    (
+        "en",
+        "account_locked",
+        "{{user}} was locked out after {{failures}} failed logins",
+    ),
+    ("en", "push_test", "{{message}}"),
+    ("en", "reset_email.sender", "Click to change your password"),
+    ("en", "reset_email.subject", "Change your password here"),
+    (
+        "en",
+        "reset_email.text",
+        "Hi {{user}}, change your password at {{link}}. \
+         The link works once and expires in {{minutes}} minutes.",
+    ),
+    (
+        "en",
+        "reset_email.html",
+        "<p><a href=\"{{link}}\">click to reset password</a></p>\
+         <p>The link works once and expires in {{minutes}} minutes.</p>",
+    ),
+    ("en", "invite_email.sender", "Door invitation"),
+    (
+        "en",
+        "invite_email.subject",
+        "You have been invited to the door",
+    ),
+    (
+        "en",
+        "invite_email.text",
+        "Hi {{user}}, set your password at {{link}}",
+    ),
+    (
+        "en",
+        "invite_email.html",
+        "<p>Hi {{user}}, you can now open the door with the app.</p>\
+         <p><a href=\"{{link}}\">Set your password</a></p>\
+         <p>The link works once and expires in {{hours}} hours.</p>",
+    ),
+    ("de", "title", "Tür"),
+    ("de", "door.lock", "{{user}} hat {{door}} abgeschlossen"),
+    ("de", "door.unlock", "{{user}} hat {{door}} aufgeschlossen"),
+    ("de", "door.open", "{{user}} hat {{door}} geöffnet"),
+    ("de", "door.close", "{{user}} hat {{door}} geschlossen"),
+    (
+        "de",
+        "door.relocked",
+        "{{door}} wurde automatisch abgeschlossen",
+    ),
+    (
+        "de",
+        "door.low_battery",
+        "Batterie von {{door}} fast leer ({{percent}} %)",
+    ),
+    ("de", "doorbell", "{{message}}"),
+    (
+        "de",
+        "failed_attempt",
+        "{{user}} wurde um {{time}} an {{door}} abgewiesen",
+    ),
+    (
+        "de",
+        "account_locked",
+        "{{user}} wurde nach {{failures}} fehlgeschlagenen Anmeldungen gesperrt",
+    ),
+    ("de", "push_test", "{{message}}"),
+    ("de", "reset_email.sender", "Passwort ändern"),
+    ("de", "reset_email.subject", "Ändere hier dein Passwort"),
+    (
+        "de",
+        "reset_email.text",
+        "Hallo {{user}}, ändere dein Passwort unter {{link}}. \
+         Der Link funktioniert einmal und läuft in {{minutes}} Minuten ab.",
+    ),
+    (
+        "de",
+        "reset_email.html",
+        "<p><a href=\"{{link}}\">Passwort zurücksetzen</a></p>\
+         <p>Der Link funktioniert einmal und läuft in {{minutes}} Minuten ab.</p>",
+    ),
+    ("de", "invite_email.sender", "Einladung zur Tür"),
+    (
+        "de",
+        "invite_email.subject",
+        "Du wurdest zur Tür eingeladen",
+    ),
+    (
+        "de",
+        "invite_email.text",
+        "Hallo {{user}}, lege dein Passwort unter {{link}} fest",
+    ),
+    (
+        "de",
+        "invite_email.html",
+        "<p>Hallo {{user}}, du kannst die Tür jetzt mit der App öffnen.</p>\
+         <p><a href=\"{{link}}\">Passwort festlegen</a></p>\
+         <p>Der Link funktioniert einmal und läuft in {{hours}} Stunden ab.</p>",
+    ),
+];
+
+/// Accepts locales like `en` or `de-AT`, normalizing their case.
+pub fn check_locale(locale: &str) -> Result<String, Error> {
+    let invalid = || Error::InvalidInput(format!("{} is not a valid locale", locale));
+    let (language, region) = match locale.trim().split_once('-') {
+        Some((language, region)) => (language, Some(region)),
+        None => (locale.trim(), None),
+    };
+    if !(2..=3).contains(&language.len()) || !language.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(invalid());
+    }
+    match region {
+        Some(region) if region.len() == 2 && region.chars().all(|c| c.is_ascii_alphabetic()) => {
+            Ok(format!(
+                "{}-{}",
+                language.to_ascii_lowercase(),
+                region.to_ascii_uppercase()
+            ))
+        }
+        Some(_) => Err(invalid()),
+        None => Ok(language.to_ascii_lowercase()),
+    }
+}
+
+fn is_known_key(key: &str) -> bool {
+    DEFAULTS.iter().any(|(_, known, _)| *known == key)
+}
+
+/// A message to render: a template key plus the values for its placeholders.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub key: String,
+    pub vars: Vec<(String, String)>,
+    /// Shown as `{{time}}`.
+    pub at: DateTime<Utc>,
+}
+
+impl Message {
+    pub fn new<T: Into<String>>(key: T) -> Self {
+        Message {
+            key: key.into(),
+            vars: Vec::new(),
+            at: Utc::now(),
+        }
+    }
+
+    pub fn var<T: ToString>(mut self, name: &str, value: T) -> Self {
+        self.vars.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Replaces `{{name}}` placeholders with their value in `vars`, escaped for
+/// HTML when `escape` is set. Unknown placeholders are left as they are and
+/// values are not expanded again.
+pub fn substitute(template: &str, vars: &[(String, String)], escape: bool) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = match after.find("}}") {
+            Some(end) => end,
+            None => {
+                rendered.push_str(&rest[start..]);
+                return rendered;
+            }
+        };
+        let name = after[..end].trim();
+        match vars.iter().find(|(var, _)| var == name) {
+            Some((_, value)) if escape => rendered.push_str(&escape_html(value)),
+            Some((_, value)) => rendered.push_str(value),
+            None => rendered.push_str(&rest[start..start + end + 4]),
+        }
+        rest = &after[end + 2..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Built-in templates overlaid with the ones admins edited.
+#[derive(Debug, Default)]
+pub struct Templates {
+    /// Edited templates by (locale, key).
+    overrides: HashMap<(String, String), String>,
+}
+
+impl Templates {
+    pub async fn load(state: &TideState) -> tide::Result<Templates> {
+        let overrides = state
+            .prisma
+            .message_templates::<MessageTemplate>(FindManyMessageTemplateArgs {
+                ..Default::default()
+            })
+            .await
+            .map_err(db_error)?
+            .into_iter()
+            .map(|template| ((template.locale, template.key), template.body))
+            .collect();
+        Ok(Templates { overrides })
+    }
+
+    /// Template for `key` in `locale`, else in its language without region,
+    /// else in the fallback locale. Edited templates win over built-in ones.
+    fn template(&self, locale: &str, key: &str) -> Option<&str> {
+        let language = locale.split('-').next().unwrap_or(locale);
+        [locale, language, FALLBACK_LOCALE]
+            .iter()
+            .find_map(|candidate| {
+                self.overrides
+                    .get(&(candidate.to_string(), key.to_string()))
+                    .map(String::as_str)
+                    .or_else(|| {
+                        DEFAULTS
+                            .iter()
+                            .find(|(default_locale, default_key, _)| {
+                                default_locale == candidate && *default_key == key
+                            })
+                            .map(|(_, _, template)| *template)
+                    })
+            })
+    }
+
+    /// Renders template `key` with the values of `message`, showing times at
+    /// `utc_offset` minutes. Values are escaped in `.html` templates.
+    pub fn render_key(
+        &self,
+        locale: &str,
+        utc_offset: i32,
+        key: &str,
+        message: &Message,
+    ) -> String {
+        let template = match self.template(locale, key) {
+            Some(template) => template,
+            None => return key.to_string(),
+        };
+        let local = message.at + Duration::minutes(utc_offset as i64);
+        let mut vars = message.vars.clone();
+        vars.push(("time".to_string(), local.format("%H:%M").to_string()));
+        substitute(template, &vars, key.ends_with(".html"))
+    }
+
+    pub fn render(&self, locale: &str, utc_offset: i32, message: &Message) -> String {
+        self.render_key(locale, utc_offset, &message.key, message)
+    }
+
+    pub fn notification(&self, locale: &str, utc_offset: i32, message: &Message) -> Notification {
+        Notification::new(
+            self.render_key(locale, utc_offset, "title", message),
+            self.render(locale, utc_offset, message),
+        )
+    }
+
+    /// Sender name and email for `message`, from its `.sender`, `.subject`,
+    /// `.text` and `.html` templates.
+    pub fn email(&self, locale: &str, to: String, message: &Message) -> (String, Email) {
+        let part =
+            |part: &str| self.render_key(locale, 0, &format!("{}.{}", message.key, part), message);
+        let email = Email {
+            to,
+            subject: part("subject"),
+            text: part("text"),
+            html: part("html"),
+        };
+        (part("sender"), email)
+    }
+}
+
+#[derive(Serialize)]
+pub struct TemplateResponse {
+    pub locale: String,
+    pub key: String,
+    pub body: String,
+    /// Edited by an admin rather than built in.
+    pub custom: bool,
+}
+
+fn template_params(req: &Request<Arc<TideState>>) -> tide::Result<(String, String)> {
+    let locale = check_locale(req.param("locale")?).map_err(Error::into_tide)?;
+    let key = req.param("key")?.to_string();
+    if !is_known_key(&key) {
+        return Err(TideError::from_str(
+            StatusCode::NotFound,
+            format!("Unknown template {}", key),
+        ));
+    }
+    Ok((locale, key))
+}
+
+async fn find_template(
+    state: &TideState,
+    locale: &str,
+    key: &str,
+) -> tide::Result<Option<MessageTemplate>> {
+    state
+        .prisma
+        .first_message_template::<MessageTemplate>(FindFirstMessageTemplateArgs {
+            filter: Some(MessageTemplateWhereInput {
+                locale: Some(MessageTemplateWhereInputLocale::String(locale.to_string())),
+                key: Some(MessageTemplateWhereInputKey::String(key.to_string())),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)
+}
+
+/// Every template, built-in ones hidden where an admin edited them.
+pub async fn list_templates(req: Request<Arc<TideState>>) -> tide::Result {
+    let templates = Templates::load(req.state()).await?;
+
+    let mut listed = DEFAULTS
+        .iter()
+        .filter(|(locale, key, _)| {
+            !templates
+                .overrides
+                .contains_key(&(locale.to_string(), key.to_string()))
+        })
+        .map(|(locale, key, body)| TemplateResponse {
+            locale: locale.to_string(),
+            key: key.to_string(),
+            body: body.to_string(),
+            custom: false,
+        })
+        .collect::<Vec<_>>();
+    listed.extend(
+        templates
+            .overrides
+            .into_iter()
+            .map(|((locale, key), body)| TemplateResponse {
+                locale,
+                key,
+                body,
+                custom: true,
+            }),
+    );
+    listed.sort_by(|a, b| (&a.locale, &a.key).cmp(&(&b.locale, &b.key)));
+
+    let mut res = tide::Response::new(StatusCode::Ok);
+    res.set_body(Body::from_json(&listed)?);
+    Ok(res)
+}
+
+/// Replaces the template `key` for `locale`, which may be a locale without
+/// built-in templates.
+pub async fn put_template(mut req: Request<Arc<TideState>>) -> tide::Result {
+    let request = req.body_json::<TemplateRequest>().await?;
+    let claims = req.claims()?;
+    let (locale, key) = template_params(&req)?;
+    let state = req.state();
+
+    if request.body.trim().is_empty() || request.body.len() > MAX_TEMPLATE_LEN {
+        return Err(Error::InvalidInput(format!(
+            "template must not be empty or longer than {} bytes",
+            MAX_TEMPLATE_LEN
+        ))
+        .into_tide());
+    }
+
+    let now = Utc::now();
+    let template = match find_template(state, &locale, &key).await? {
+        Some(template) => state
+            .prisma
+            .update_message_template::<MessageTemplate>(UpdateOneMessageTemplateArgs {
+                data: MessageTemplateUpdateInput {
+                    body: Some(MessageTemplateUpdateInputBody::String(request.body)),
+                    updated_at: Some(MessageTemplateUpdateInputUpdatedAt::DateTime(now)),
+                    ..Default::default()
+                },
+                filter: MessageTemplateWhereUniqueInput {
+                    id: Some(template.id),
+                    ..Default::default()
+                },
+            })
+            .await
+            .map_err(db_error)?,
+        None => state
+            .prisma
+            .create_message_template::<MessageTemplate>(MessageTemplateCreateInput {
+                locale,
+                key,
+                body: request.body,
+                updated_at: now,
+            })
+            .await
+            .map_err(db_error)?,
+    };
+
+    let event = NewEvent::new("template_updated", EventResult::Allowed)
+        .source(&req)
+        .user(Some(claims.sub as i32), Some(claims.email.clone()))
+        .reason(format!("{} {}", template.locale, template.key));
+    events::record(state, event).await;
+
+    let mut res = tide::Response::new(StatusCode::Ok);
+    res.set_body(Body::from_json(&TemplateResponse {
+        locale: template.locale,
+        key: template.key,
+        body: template.body,
+        custom: true,
+    })?);
+    Ok(res)
+}
+
+/// Drops an edited template, going back to the built-in one.
+pub async fn delete_template(req: Request<Arc<TideState>>) -> tide::Result {
+    let claims = req.claims()?;
+    let (locale, key) = template_params(&req)?;
+    let state = req.state();
+
+    let deleted = state
+        .prisma
+        .delete_many_message_templates(DeleteManyMessageTemplateArgs {
+            filter: Some(MessageTemplateWhereInput {
+                locale: Some(MessageTemplateWhereInputLocale::String(locale.clone())),
+                key: Some(MessageTemplateWhereInputKey::String(key.clone())),
+                ..Default::default()
+            }),
+        })
+        .await
+        .map_err(db_error)?;
+    if deleted.count == 0 {
+        return Err(TideError::from_str(
+            StatusCode::NotFound,
+            "Template was not edited",
+        ));
+    }
+
+    let event = NewEvent::new("template_reset", EventResult::Allowed)
+        .source(&req)
+        .user(Some(claims.sub as i32), Some(claims.email.clone()))
+        .reason(format!("{} {}", locale, key));
+    events::record(state, event).await;
+
+    Ok(tide::Response::new(StatusCode::NoContent))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_locale, substitute, Message, Templates, DEFAULTS, FALLBACK_LOCALE};
+    use chrono::{TimeZone, Utc};
+    use std::collections::BTreeSet;
+
+    fn placeholders(template: &str) -> BTreeSet<&str> {
+        template
+            .split("{{")
+            .skip(1)
+            .filter_map(|rest| rest.split_once("}}"))
+            .map(|(name, _)| name.trim())
+            .collect()
+    }
+
+    #[test]
+    fn every_locale_fills_in_what_the_fallback_does() {
+        for (locale, key, template) in DEFAULTS {
+            let fallback = DEFAULTS
+                .iter()
+                .find(|(other, other_key, _)| *other == FALLBACK_LOCALE && other_key == key)
+                .map(|(_, _, fallback)| *fallback)
+                .unwrap_or_else(|| panic!("{} has no {} default", key, FALLBACK_LOCALE));
+            assert_eq!(
+                placeholders(template),
+                placeholders(fallback),
+                "{} {} uses other placeholders than {}",
+                locale,
+                key,
+                FALLBACK_LOCALE
+            );
+        }
+    }
+
+    #[test]
+    fn substitutes_known_placeholders_once() {
+        let vars = vec![
+            ("user".to_string(), "<Ann>".to_string()),
+            ("door".to_string(), "{{user}}".to_string()),
+        ];
+        assert_eq!(
+            substitute("{{ user }} opened {{door}} {{unknown}}", &vars, false),
+            "<Ann> opened {{user}} {{unknown}}"
+        );
+        assert_eq!(
+            substitute("<b>{{user}}</b>", &vars, true),
+            "<b>&lt;Ann&gt;</b>"
+        );
+        assert_eq!(substitute("broken {{user", &vars, false), "broken {{user");
+    }
+
+    #[test]
+    fn renders_in_the_users_locale_with_fallbacks() {
+        let mut templates = Templates::default();
+        templates.overrides.insert(
+            ("fr".to_string(), "door.open".to_string()),
+            "{{user}} a ouvert {{door}} à {{time}}".to_string(),
+        );
+        let message = Message {
+            at: Utc.ymd(2022, 10, 18).and_hms(18, 5, 0),
+            ..Message::new("door.open")
+                .var("user", "Ann")
+                .var("door", "Haustür")
+        };
+
+        assert_eq!(
+            templates.render("en", 0, &message),
+            "Ann opened the Haustür"
+        );
+        assert_eq!(
+            templates.render("de-AT", 0, &message),
+            "Ann hat Haustür geöffnet"
+        );
+        assert_eq!(
+            templates.render("fr", 120, &message),
+            "Ann a ouvert Haustür à 20:05"
+        );
+        assert_eq!(templates.notification("fr", 0, &message).title, "Door");
+        assert_eq!(templates.render("en", 0, &Message::new("nope")), "nope");
+
+        assert_eq!(check_locale("de-at").unwrap(), "de-AT");
+        assert!(check_locale("german").is_err());
+        assert!(check_locale("../en").is_err());
+    }
+}
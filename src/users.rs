@@ -0,0 +1,350 @@
+use crate::{
+    auth::Role,
+    devices,
+    error::{db_error, Error},
+    events::{self, EventResult, NewEvent},
+    lockout, mail,
+    middleware::ClaimsExt,
+    preferences,
+    prisma::{
+        AccessGrantWhereInput, AccessGrantWhereInputUserId, AccessScheduleWhereInput,
+        AccessScheduleWhereInputUserId, DeleteManyAccessGrantArgs, DeleteManyAccessScheduleArgs,
+        DeleteManyPasswordResetTokenArgs, FindManyUserArgs, PasswordResetTokenWhereInput,
+        PasswordResetTokenWhereInputUserId, SortOrder, UpdateOneUserArgs, User, UserCreateInput,
+        UserOrderByInput, UserUpdateInput, UserUpdateInputDisabled, UserUpdateInputLocale,
+        UserUpdateInputName, UserUpdateInputRole, UserWhereUniqueInput,
+    },
+    reset, session,
+    templates::{check_locale, Message, Templates},
+    utils::{check_email, check_password, random_token},
+    CreateUserRequest, TideState, UpdateUserRequest,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::sync::Arc;
+use tide::{Body, Error as TideError, Request, StatusCode};
+
+/// A user as admins see it, without the password hash.
+#[derive(Serialize)]
+pub struct UserResponse {
+    pub id: i32,
+    pub email: String,
+    pub name: String,
+    pub role: String,
+    pub disabled: bool,
+    pub must_change_password: bool,
+    pub locale: String,
+    /// Set while the account is locked after too many failed logins.
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+impl From<User> for UserResponse {
+    fn from(user: User) -> Self {
+        UserResponse {
+            id: user.id,
+            email: user.email,
+            name: user.name,
+            role: user.role,
+            disabled: user.disabled,
+            must_change_password: user.must_change_password,
+            locale: user.locale,
+            locked_until: user.locked_until.filter(|until| *until > Utc::now()),
+        }
+    }
+}
+
+fn user_id(req: &Request<Arc<TideState>>) -> tide::Result<i32> {
+    req.param("user_id")?
+        .parse::<i32>()
+        .map_err(|e| TideError::from_str(StatusCode::BadRequest, format!("Invalid user id: {}", e)))
+}
+
+fn parse_role(role: &str) -> tide::Result<Role> {
+    Role::parse(role)
+        .ok_or_else(|| Error::InvalidInput(format!("unknown role {}", role)).into_tide())
+}
+
+pub fn parse_name(name: &str) -> tide::Result<String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(Error::InvalidInput("name must not be empty".to_string()).into_tide());
+    }
+    Ok(name.to_string())
+}
+
+async fn find_user(state: &TideState, id: i32) -> tide::Result<User> {
+    state
+        .prisma
+        .user::<User>(UserWhereUniqueInput {
+            id: Some(id),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| TideError::from_str(StatusCode::NotFound, "User not found"))
+}
+
+fn json(status: StatusCode, body: &impl Serialize) -> tide::Result {
+    let mut res = tide::Response::new(status);
+    res.set_body(Body::from_json(body)?);
+    Ok(res)
+}
+
+pub async fn list_users(req: Request<Arc<TideState>>) -> tide::Result {
+    let users = req
+        .state()
+        .prisma
+        .users::<User>(FindManyUserArgs {
+            order_by: Some(vec![UserOrderByInput {
+                id: Some(SortOrder::Asc),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?
+        .into_iter()
+        .map(UserResponse::from)
+        .collect::<Vec<_>>();
+
+    json(StatusCode::Ok, &users)
+}
+
+/// Creates an account that has to pick its own password before the first
+/// login. With a `password` the admin hands that temporary password over;
+/// without one the user is emailed an invite link to set it.
+pub async fn create_user(mut req: Request<Arc<TideState>>) -> tide::Result {
+    let request = req.body_json::<CreateUserRequest>().await?;
+    let claims = req.claims()?;
+    let state = req.state();
+
+    let email = check_email(&request.email).map_err(Error::into_tide)?;
+    let name = parse_name(&request.name)?;
+    let role = match &request.role {
+        Some(role) => parse_role(role)?,
+        None => Role::User,
+    };
+    let locale = match &request.locale {
+        Some(locale) => check_locale(locale).map_err(Error::into_tide)?,
+        None => state.config.locale.clone(),
+    };
+    let mailgun = match (&request.password, &state.config.mailgun) {
+        (Some(_), _) => None,
+        (None, Some(mailgun)) => Some(mailgun),
+        (None, None) => {
+            return Err(TideError::from_str(
+                StatusCode::ServiceUnavailable,
+                "Invite emails are not configured, set a temporary password instead",
+            ))
+        }
+    };
+    let password = match &request.password {
+        Some(password) => {
+            check_password(password).map_err(Error::into_tide)?;
+            password.clone()
+        }
+        // nobody knows this one, the invite link replaces it
+        None => random_token(32),
+    };
+
+    let existing = state
+        .prisma
+        .user::<User>(UserWhereUniqueInput {
+            email: Some(email.clone()),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?;
+    if existing.is_some() {
+        return Err(TideError::from_str(
+            StatusCode::Conflict,
+            "A user with this email already exists",
+        ));
+    }
+
+    let user = state
+        .prisma
+        .create_user::<User>(UserCreateInput {
+            email,
+            name,
+            password: state
+                .hasher
+                .hash(&password)
+                .map_err(|e| Error::from(e).into_tide())?,
+            role: role.as_str().to_string(),
+            locale,
+            disabled: false,
+            must_change_password: true,
+            failed_logins: 0,
+            last_failed_login: None,
+            locked_until: None,
+        })
+        .await
+        .map_err(db_error)?;
+
+    if let Some(mailgun) = mailgun {
+        let lifetime = Duration::hours(state.config.invite_token_hours);
+        let token = reset::issue(state, &user, lifetime).await?;
+        let message = Message::new("invite_email")
+            .var("user", &user.name)
+            .var(
+                "link",
+                format!("{}?token={}", state.config.reset_url, token),
+            )
+            .var("hours", state.config.invite_token_hours);
+        let templates = Templates::load(state).await?;
+        let (sender, email) = templates.email(&user.locale, user.email.clone(), &message);
+        mail::send(mailgun, &sender, email);
+    }
+
+    let event = NewEvent::new("user_created", EventResult::Allowed)
+        .source(&req)
+        .user(Some(user.id), Some(user.email.clone()))
+        .reason(format!("created by {}", claims.email));
+    events::record(state, event).await;
+
+    json(StatusCode::Created, &UserResponse::from(user))
+}
+
+/// Renames a user, changes their role or (re-)enables them. Role changes and
+/// disabling end the user's sessions so their tokens stop carrying the old role.
+pub async fn update_user(mut req: Request<Arc<TideState>>) -> tide::Result {
+    let request = req.body_json::<UpdateUserRequest>().await?;
+    let claims = req.claims()?;
+    let state = req.state();
+    let user = find_user(state, user_id(&req)?).await?;
+
+    let role = request.role.as_deref().map(parse_role).transpose()?;
+    let name = request.name.as_deref().map(parse_name).transpose()?;
+    let locale = request
+        .locale
+        .as_deref()
+        .map(check_locale)
+        .transpose()
+        .map_err(Error::into_tide)?;
+    let demoted = matches!(&role, Some(role) if *role != Role::Admin);
+    if user.id as usize == claims.sub && (demoted || request.disabled == Some(true)) {
+        return Err(
+            Error::InvalidInput("admins cannot demote or disable themselves".to_string())
+                .into_tide(),
+        );
+    }
+    let role_changed = matches!(&role, Some(role) if role.as_str() != user.role);
+
+    let updated = state
+        .prisma
+        .update_user::<User>(UpdateOneUserArgs {
+            data: UserUpdateInput {
+                name: name.map(UserUpdateInputName::String),
+                role: role.map(|role| UserUpdateInputRole::String(role.as_str().to_string())),
+                disabled: request.disabled.map(UserUpdateInputDisabled::Boolean),
+                locale: locale.map(UserUpdateInputLocale::String),
+                ..Default::default()
+            },
+            filter: UserWhereUniqueInput {
+                id: Some(user.id),
+                ..Default::default()
+            },
+        })
+        .await
+        .map_err(db_error)?;
+
+    if role_changed || (updated.disabled && !user.disabled) {
+        session::revoke_all(state, updated.id).await?;
+    }
+
+    let event = NewEvent::new("user_updated", EventResult::Allowed)
+        .source(&req)
+        .user(Some(updated.id), Some(updated.email.clone()))
+        .reason(format!(
+            "role {}, {}, by {}",
+            updated.role,
+            if updated.disabled {
+                "disabled"
+            } else {
+                "enabled"
+            },
+            claims.email
+        ));
+    events::record(state, event).await;
+
+    json(StatusCode::Ok, &UserResponse::from(updated))
+}
+
+/// Deletes a user along with their grants, schedules, sessions, devices,
+/// notification preferences and reset tokens. Their access events stay for the audit log.
+pub async fn delete_user(req: Request<Arc<TideState>>) -> tide::Result {
+    let claims = req.claims()?;
+    let state = req.state();
+    let user = find_user(state, user_id(&req)?).await?;
+    if user.id as usize == claims.sub {
+        return Err(Error::InvalidInput("admins cannot delete themselves".to_string()).into_tide());
+    }
+
+    state
+        .prisma
+        .delete_many_access_grants(DeleteManyAccessGrantArgs {
+            filter: Some(AccessGrantWhereInput {
+                user_id: Some(AccessGrantWhereInputUserId::Int(user.id)),
+                ..Default::default()
+            }),
+        })
+        .await
+        .map_err(db_error)?;
+    state
+        .prisma
+        .delete_many_access_schedules(DeleteManyAccessScheduleArgs {
+            filter: Some(AccessScheduleWhereInput {
+                user_id: Some(AccessScheduleWhereInputUserId::Int(user.id)),
+                ..Default::default()
+            }),
+        })
+        .await
+        .map_err(db_error)?;
+    state
+        .prisma
+        .delete_many_password_reset_tokens(DeleteManyPasswordResetTokenArgs {
+            filter: Some(PasswordResetTokenWhereInput {
+                user_id: Some(PasswordResetTokenWhereInputUserId::Int(user.id)),
+                ..Default::default()
+            }),
+        })
+        .await
+        .map_err(db_error)?;
+    session::revoke_all(state, user.id).await?;
+    devices::remove_all(state, user.id).await?;
+    preferences::remove(state, user.id).await?;
+
+    state
+        .prisma
+        .delete_user::<User>(UserWhereUniqueInput {
+            id: Some(user.id),
+            ..Default::default()
+        })
+        .await
+        .map_err(db_error)?;
+
+    let event = NewEvent::new("user_deleted", EventResult::Allowed)
+        .source(&req)
+        .user(Some(user.id), Some(user.email))
+        .reason(format!("deleted by {}", claims.email));
+    events::record(state, event).await;
+
+    Ok(tide::Response::new(StatusCode::NoContent))
+}
+
+/// Lifts a lockout and clears the failed login count of a user.
+pub async fn unlock_user(req: Request<Arc<TideState>>) -> tide::Result {
+    let claims = req.claims()?;
+    let state = req.state();
+    let user = find_user(state, user_id(&req)?).await?;
+    let user = lockout::reset_account(state, &user).await?;
+
+    let event = NewEvent::new("account_unlocked", EventResult::Allowed)
+        .source(&req)
+        .user(Some(user.id), Some(user.email.clone()))
+        .reason(format!("unlocked by {}", claims.email));
+    events::record(state, event).await;
+
+    json(StatusCode::Ok, &UserResponse::from(user))
+}
@@ -1,23 +1,233 @@
-use crate::auth::SALT;
-use argon2::Config;
+use crate::{config::PasswordSettings, error::Error};
+use argon2::{Config, ThreadMode, Variant, Version};
+use rand::RngCore;
+
+const SALT_LEN: usize = 16;
+pub const MIN_PASSWORD_LEN: usize = 8;
+pub const MAX_PASSWORD_LEN: usize = 128;
+const MAX_EMAIL_LEN: usize = 254;
+
+/// Random token of `bytes` bytes, hex encoded.
+pub fn random_token(bytes: usize) -> String {
+    let mut buf = vec![0u8; bytes];
+    rand::thread_rng().fill_bytes(&mut buf);
+    to_hex(&buf)
+}
+
+/// SHA-256 of `token`, hex encoded. Bearer secrets such as reset and refresh
+/// tokens are stored this way so a leaked database cannot be replayed.
+pub fn sha256_hex(token: &str) -> String {
+    to_hex(&openssl::sha::sha256(token.as_bytes()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Rejects passwords too short or too simple to be worth storing. Besides the
+/// length, a password needs at least three of lowercase and uppercase letters,
+/// digits and other characters.
+pub fn check_password(password: &str) -> Result<(), Error> {
+    let len = password.chars().count();
+    if len < MIN_PASSWORD_LEN || len > MAX_PASSWORD_LEN {
+        return Err(Error::InvalidInput(format!(
+            "password must be between {} and {} characters long",
+            MIN_PASSWORD_LEN, MAX_PASSWORD_LEN
+        )));
+    }
+
+    let classes = [
+        password.chars().any(|c| c.is_lowercase()),
+        password.chars().any(|c| c.is_uppercase()),
+        password.chars().any(|c| c.is_ascii_digit()),
+        password.chars().any(|c| !c.is_alphanumeric()),
+    ];
+    if classes.iter().filter(|present| **present).count() < 3 {
+        return Err(Error::InvalidInput(
+            "password must mix at least three of lowercase, uppercase, digits and symbols"
+                .to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that `email` looks deliverable and returns it without surrounding
+/// whitespace. Only the shape is checked; the invite email proves the rest.
+pub fn check_email(email: &str) -> Result<String, Error> {
+    let email = email.trim();
+    let invalid = || Error::InvalidInput(format!("{} is not a valid email address", email));
+
+    if email.len() > MAX_EMAIL_LEN || email.chars().any(|c| c.is_whitespace()) {
+        return Err(invalid());
+    }
+    let (local, domain) = email.split_once('@').ok_or_else(invalid)?;
+    if local.is_empty() || local.len() > 64 || domain.contains('@') {
+        return Err(invalid());
+    }
+    let labels = domain.split('.').collect::<Vec<_>>();
+    if labels.len() < 2 || labels.iter().any(|label| label.is_empty()) {
+        return Err(invalid());
+    }
+    Ok(email.to_string())
+}
+
+/// Outcome of checking a password against a stored hash.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verification {
+    Mismatch,
+    Valid,
+    /// The password is right but the hash uses older parameters and should be replaced.
+    NeedsRehash,
+}
+
+impl Verification {
+    pub fn matches(&self) -> bool {
+        *self != Verification::Mismatch
+    }
+}
 
 pub struct Hasher {
-    salt: [u8; 32],
     config: Config<'static>,
+    /// Checked against when there is no stored hash, see `verify_nothing`.
+    dummy: String,
 }
 
 impl Hasher {
-    pub fn new() -> Self {
-        let salt = SALT;
-        let config = Config::default();
-        Self { salt, config }
+    pub fn new(settings: &PasswordSettings) -> Self {
+        let config = Config {
+            variant: Variant::Argon2id,
+            version: Version::Version13,
+            mem_cost: settings.memory_kib,
+            time_cost: settings.iterations,
+            lanes: settings.parallelism,
+            thread_mode: ThreadMode::from_threads(settings.parallelism),
+            ..Config::default()
+        };
+        let dummy = argon2::hash_encoded(b"no such account", &[0; SALT_LEN], &config)
+            .expect("password settings are validated with the config");
+        Self { config, dummy }
     }
 
+    /// Hashes `password` with a fresh random salt.
     pub fn hash(&self, password: &str) -> Result<String, argon2::Error> {
-        argon2::hash_encoded(password.as_bytes(), &self.salt, &self.config)
+        let mut salt = [0u8; SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        argon2::hash_encoded(password.as_bytes(), &salt, &self.config)
+    }
+
+    pub fn verify(&self, password: &str, hashed: &str) -> Result<Verification, argon2::Error> {
+        if !argon2::verify_encoded(hashed, password.as_bytes())? {
+            return Ok(Verification::Mismatch);
+        }
+        if self.is_current(hashed) {
+            Ok(Verification::Valid)
+        } else {
+            Ok(Verification::NeedsRehash)
+        }
     }
 
-    pub fn verify(&self, password: &str, hashed: &str) -> Result<bool, argon2::Error> {
-        argon2::verify_encoded(hashed, password.as_bytes())
+    /// Takes as long as `verify` without a hash to check, so a login for an
+    /// unknown email cannot be told apart by how long the answer takes.
+    pub fn verify_nothing(&self, password: &str) {
+        let _ = argon2::verify_encoded(&self.dummy, password.as_bytes());
     }
-}
\ No newline at end of file
+
+    /// Whether `hashed` was made with this hasher's variant and parameters, e.g.
+    /// `$argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>`.
+    fn is_current(&self, hashed: &str) -> bool {
+        let parts = hashed.split('$').collect::<Vec<_>>();
+        let expected_params = format!(
+            "m={},t={},p={}",
+            self.config.mem_cost, self.config.time_cost, self.config.lanes
+        );
+        match parts.as_slice() {
+            ["", variant, version, params, _salt, _hash] => {
+                *variant == self.config.variant.as_lowercase_str()
+                    && *version == format!("v={}", self.config.version.as_u32())
+                    && *params == expected_params
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_email, check_password, random_token, sha256_hex, Hasher, Verification};
+    use crate::config::PasswordSettings;
+
+    fn settings(iterations: u32) -> PasswordSettings {
+        PasswordSettings {
+            memory_kib: 64,
+            iterations,
+            parallelism: 1,
+        }
+    }
+
+    #[test]
+    fn salts_each_hash_and_flags_outdated_parameters() {
+        let hasher = Hasher::new(&settings(1));
+        let first = hasher.hash("Password@123").unwrap();
+        let second = hasher.hash("Password@123").unwrap();
+        assert_ne!(first, second);
+
+        assert_eq!(
+            hasher.verify("Password@123", &first).unwrap(),
+            Verification::Valid
+        );
+        assert_eq!(
+            hasher.verify("wrong", &first).unwrap(),
+            Verification::Mismatch
+        );
+
+        let stronger = Hasher::new(&settings(2));
+        assert_eq!(
+            stronger.verify("Password@123", &first).unwrap(),
+            Verification::NeedsRehash
+        );
+
+        // hashes from before per-hash salts used argon2i with the library defaults
+        let legacy =
+            argon2::hash_encoded(b"Password@123", &[7; 32], &argon2::Config::default()).unwrap();
+        assert_eq!(
+            hasher.verify("Password@123", &legacy).unwrap(),
+            Verification::NeedsRehash
+        );
+    }
+
+    #[test]
+    fn tokens_are_random_and_stored_hashed() {
+        let token = random_token(32);
+        assert_eq!(token.len(), 64);
+        assert_ne!(token, random_token(32));
+
+        assert_eq!(sha256_hex(&token), sha256_hex(&token));
+        assert_eq!(
+            sha256_hex("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn rejects_weak_passwords_and_malformed_emails() {
+        assert!(check_password("Password@123").is_ok());
+        assert!(check_password("correct horse Battery").is_ok());
+        assert!(check_password("Pa@1").is_err());
+        assert!(check_password("alllowercaseletters").is_err());
+        assert!(check_password("lowercase123").is_err());
+        assert!(check_password(&"Aa1!".repeat(40)).is_err());
+
+        assert_eq!(check_email(" mum@example.com ").unwrap(), "mum@example.com");
+        for email in [
+            "mum",
+            "mum@",
+            "@example.com",
+            "mum@example",
+            "a b@example.com",
+            "mum@@example.com",
+            "mum@example..com",
+        ] {
+            assert!(check_email(email).is_err(), "{}", email);
+        }
+    }
+}